@@ -0,0 +1,74 @@
+
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use super::error::Error;
+
+// Container formats that can be loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Mpeg,
+    Mp4,
+}
+
+// Work out the format of the file from its first few bytes, without parsing the rest of it
+// The extension is only used if the contents are inconclusive
+pub fn detect(path: &Path) -> Result<Option<Format>, Error> {
+    let mut file = fs::File::open(path)?;
+    Ok(detect_from_reader(&mut file)?.or_else(|| from_extension(path)))
+}
+
+// Same as `detect`, but there's no extension to fall back on
+pub fn detect_from_reader<R: Read + Seek>(reader: &mut R) -> Result<Option<Format>, Error> {
+    reader.seek(SeekFrom::Start(0))?;
+
+    let mut header = Vec::new();
+    reader.take(16).read_to_end(&mut header)?;
+
+    Ok(sniff(&header))
+}
+
+fn sniff(header: &[u8]) -> Option<Format> {
+    if header.starts_with(b"ID3") {
+        return Some(Format::Mpeg);
+    }
+
+    // Every mp4 file should start with `ftyp`, but older (QuickTime) files may start with any top-level atom
+    if header.len() >= 8 {
+        match &header[4..8] {
+            b"ftyp" | b"moov" | b"mdat" | b"free" | b"skip" | b"wide" => return Some(Format::Mp4),
+            _ => ()
+        }
+    }
+
+    if is_mpeg_frame_header(header) {
+        return Some(Format::Mpeg);
+    }
+
+    None
+}
+
+// Check for the frame sync along with valid version, layer, bitrate and sample rate indices
+fn is_mpeg_frame_header(header: &[u8]) -> bool {
+    if header.len() < 4 || header[0] != 0xff || header[1] & 0xe0 != 0xe0 {
+        return false;
+    }
+
+    let version = (header[1] >> 3) & 0b11;
+    let layer = (header[1] >> 1) & 0b11;
+    let bitrate = header[2] >> 4;
+    let samplerate = (header[2] >> 2) & 0b11;
+
+    version != 0b01 && layer != 0b00 && bitrate != 0b1111 && samplerate != 0b11
+}
+
+fn from_extension(path: &Path) -> Option<Format> {
+    let ext = path.extension()?.to_str()?.to_lowercase();
+
+    match ext.as_str() {
+        "mp3" | "mp2" | "mpga" => Some(Format::Mpeg),
+        "m4a" | "m4b" | "m4p" | "m4v" | "mp4" => Some(Format::Mp4),
+        _ => None
+    }
+}
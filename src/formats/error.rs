@@ -0,0 +1,73 @@
+
+use std::error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    // The file uses a part of the format that isn't handled yet (eg. encrypted frames)
+    Unsupported(String),
+
+    // The tag doesn't follow the spec
+    // `offset` is the position in the file where the problem was found and `id` is the tag, atom or frame being read
+    // Problems inside a frame or atom body are reported at the start of that frame or atom, or at the part of
+    // the body that couldn't be read
+    Malformed {
+        offset: u64,
+        id: String,
+        reason: String
+    },
+
+    // The file isn't in any of the formats that can be loaded
+    UnsupportedFormat,
+
+    Io(io::Error),
+}
+
+impl Error {
+    pub(crate) fn unsupported(feature: &str) -> Self {
+        Error::Unsupported(feature.to_string())
+    }
+
+    pub(crate) fn malformed(offset: u64, id: &str, reason: &str) -> Self {
+        Error::Malformed{ offset, id: id.to_string(), reason: reason.to_string() }
+    }
+
+    // Parsers report offsets relative to the buffer they were handed, this makes them relative to the file
+    pub(crate) fn offset_by(self, base: u64) -> Self {
+        self.map_offset(|offset| offset + base)
+    }
+
+    pub(crate) fn map_offset<F: FnOnce(u64) -> u64>(self, f: F) -> Self {
+        match self {
+            Error::Malformed{ offset, id, reason } => Error::Malformed{ offset: f(offset), id, reason },
+            err => err
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Unsupported(feature) => write!(f, "{} not currently supported", feature),
+            Error::Malformed{ offset, id, reason } => write!(f, "Malformed `{}` at byte {}: {}", id, offset, reason),
+            Error::UnsupportedFormat => write!(f, "Unrecognized file format"),
+            Error::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
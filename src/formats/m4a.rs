@@ -1,110 +1,300 @@
 #![allow(non_snake_case)]
 
+use std::cmp::min;
 use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
-use std::iter::FromIterator;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path;
 use std::rc;
-use std::{str, u32};
+use std::str;
 
 use byteorder::{BigEndian, ByteOrder};
 
+use super::error::Error;
 use super::meta;
 use super::utils;
 
 struct Properties;
 
-// TODO: How do I restrict the visibility to just this file ???
+// Offset and length (including the header) in the file, name, and child atoms
 #[derive(Debug)]
-pub(crate) enum Atom {
-    Atom(u64, u64, String, Vec<Atom>),
+pub struct Atom {
+    offset: u64,
+    len: u64,
+    name: String,
+    children: Vec<Atom>
+}
+
+impl Atom {
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    // Includes the header, so an atom is never empty
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Only atoms which are known to be containers have their children read
+    pub fn children(&self) -> &[Atom] {
+        &self.children
+    }
 }
 
 pub struct File {
     tag: rc::Rc<Tag>,
-    _atoms: Vec<Atom>,
+    atoms: Vec<Atom>,
+
+    // Files read from other sources can't be saved
+    path: Option<path::PathBuf>,
     _props: Vec<Properties>
 }
 
 impl File {
     pub fn open<P: AsRef<path::Path>>(path: P) -> Result<Self, Error> {
-        let mut file = fs::File::open(path)?;
+        let mut file = fs::File::open(&path)?;
 
-        let mut atoms = Vec::new();
-        while let Ok(atom) = read_atom(&mut file) {
-            atoms.push(atom);
+        let mut ret = Self::from_reader(&mut file)?;
+        ret.path = Some(path.as_ref().to_path_buf());
+        Ok(ret)
+    }
+
+    pub fn from_reader<R: Read + Seek>(reader: &mut R) -> Result<Self, Error> {
+        reader.seek(SeekFrom::Start(0))?;
+        let atoms = read_atoms(reader);
+
+        // Must have a 'moov' atom, files without a tag get an empty one
+        if find_path(&atoms, &["moov"]).is_none() {
+            return Err(Error::malformed(0, "moov", "Required atom (moov) not found"));
         }
+        let tag = read_tag(&atoms, reader)?;
+
+        // TODO: Read properties
+        // if let Some(props) = read_properties(&atoms, reader) {}
 
-        // Must have a 'moov' atom
-        let moov = atoms.iter().position(|Atom::Atom(_, _, name, _)| name == "moov");
-        if let Some(moov_index) = moov {
-            if let Some(tag) = read_tag(moov_index, &atoms, &mut file) {
+        Ok(Self{
+            tag: rc::Rc::new(tag),
+            atoms,
+            path: None,
+            _props: Vec::new()
+        })
+    }
 
-                // TODO: Read properties
-                // if let Some(props) = read_properties(moov_index, &atoms, &mut file) {}
+    // The top level atoms in the file
+    pub fn atoms(&self) -> &[Atom] {
+        &self.atoms
+    }
 
-                return Ok(Self{
-                    tag: rc::Rc::new(tag),
-                    _atoms: atoms,
-                    _props: Vec::new()
-                });
-            }
-        }
+    pub fn mp4_tag(&self) -> &Tag {
+        &self.tag
+    }
+
+    pub fn mp4_tag_mut(&mut self) -> &mut Tag {
+        rc::Rc::make_mut(&mut self.tag)
+    }
+
+    // Regenerate the `ilst` atom from the current tag and write it back into the file
+    // The file is written to a temporary file next to it, which then replaces the original
+    pub fn write_tag(&mut self) -> Result<(), Error> {
+        let path = match self.path {
+            Some(ref path) => path.clone(),
+            None => return Err(Error::unsupported("Saving files which weren't opened from a path"))
+        };
+        let mut data = fs::read(&path)?;
+        splice_tag(&self.atoms, &mut data, self.tag.render())?;
+
+        utils::replace_file(&path, |temp| temp.write_all(&data))?;
+
+        let mut file = fs::File::open(&path)?;
+        self.atoms = read_atoms(&mut file);
+        Ok(())
+    }
+}
+
+// Where the tag lives in the atom tree
+const TAG_PATH: [&str; 4] = ["moov", "udta", "meta", "ilst"];
+
+// Replace the `ilst` atom in `data` (the whole file) with `ilst`, creating `udta` and `meta` if they're missing
+// Every atom containing `ilst` is resized and chunk offsets into `mdat` are shifted to match
+fn splice_tag(atoms: &[Atom], data: &mut Vec<u8>, ilst: Vec<u8>) -> Result<(), Error> {
+    let parents = (1..=TAG_PATH.len()).rev()
+        .find_map(|depth| find_path(atoms, &TAG_PATH[..depth]))
+        .ok_or_else(|| Error::malformed(0, "moov", "Required atom (moov) not found"))?;
+
+    // Either replace the existing `ilst`, or add the missing atoms at the end of the deepest one that exists
+    let (position, length, parents, atom) = if parents.len() == TAG_PATH.len() {
+        let atom = parents[parents.len() - 1];
+        (atom.offset as usize, atom.len as usize, &parents[..(parents.len() - 1)], ilst)
+    } else {
+        let atom = parents[parents.len() - 1];
+        ((atom.offset + atom.len) as usize, 0, &parents[..], renderContainers(&TAG_PATH[parents.len()..(TAG_PATH.len() - 1)], ilst))
+    };
+    let delta = atom.len() as i64 - length as i64;
+
+    // Every chunk offset is stored relative to the start of the file, so anything after the tag moves
+    let mut chunk_offsets = Vec::new();
+    find_chunk_offsets(atoms, &mut chunk_offsets);
+    for atom in chunk_offsets {
+        let range = (atom.offset as usize)..((atom.offset + atom.len) as usize);
+        update_chunk_offsets(&atom.name, &mut data[range], (position + length) as u64, delta)?;
+    }
 
-        Err(Error::new(ErrorKind::InvalidData, "Required atom (moov > udta > meta > ilst) not found"))
+    // The parents all start before the tag, so their offsets aren't affected by the splice
+    for atom in parents {
+        update_atom_length(&mut data[(atom.offset as usize)..], (atom.len as i64 + delta) as u64)?;
     }
+
+    data.splice(position..(position + length), atom);
+    Ok(())
 }
 
 impl meta::File for File {
-    fn tag(&self) -> rc::Rc<meta::Tag> {
+    fn tag(&self) -> rc::Rc<dyn meta::Tag> {
         self.tag.clone()
     }
+
+    fn tag_mut(&mut self) -> Option<&mut dyn meta::Tag> {
+        let tag: &mut Tag = rc::Rc::make_mut(&mut self.tag);
+        Some(tag)
+    }
+
+    fn save(&mut self) -> Result<(), Error> {
+        self.write_tag()
+    }
+}
+
+// Find the chain of atoms leading to the atom at the end of `path`
+fn find_path<'a>(atoms: &'a [Atom], path: &[&str]) -> Option<Vec<&'a Atom>> {
+    let (first, rest) = path.split_first()?;
+    let atom = atoms.iter().find(|atom| atom.name == *first)?;
+    let mut chain = vec![atom];
+
+    if !rest.is_empty() {
+        chain.extend(find_path(&atom.children, rest)?);
+    }
+
+    Some(chain)
+}
+
+fn find_chunk_offsets<'a>(atoms: &'a [Atom], found: &mut Vec<&'a Atom>) {
+    for atom in atoms {
+        match atom.name.as_str() {
+            "stco" | "co64" | "tfhd" => found.push(atom),
+            _ => find_chunk_offsets(&atom.children, found)
+        }
+    }
+}
+
+// Shift every offset in `buf` (the complete atom) which points at or past `position` by `delta`
+fn update_chunk_offsets(name: &str, buf: &mut [u8], position: u64, delta: i64) -> Result<(), Error> {
+    let shift = |offset: u64| if offset >= position { (offset as i64 + delta) as u64 } else { offset };
+    let header = atom_header_size(buf);
+
+    match name {
+        // Header, version/flags, entry count, then a list of 32-bit offsets
+        "stco" => {
+            let mut pos = header + 8;
+            while pos + 4 <= buf.len() {
+                let offset = shift(BigEndian::read_u32(&buf[pos..]) as u64);
+                if offset > u32::MAX as u64 {
+                    return Err(Error::unsupported("Moving chunk offsets past 4GB"));
+                }
+
+                BigEndian::write_u32(&mut buf[pos..], offset as u32);
+                pos += 4;
+            }
+        },
+
+        // Same as `stco` but with 64-bit offsets
+        "co64" => {
+            let mut pos = header + 8;
+            while pos + 8 <= buf.len() {
+                let offset = shift(BigEndian::read_u64(&buf[pos..]));
+                BigEndian::write_u64(&mut buf[pos..], offset);
+                pos += 8;
+            }
+        },
+
+        // Movie fragments may specify an explicit base data offset
+        "tfhd" => {
+            let pos = header + 8;
+            let has_base_offset = buf.len() >= pos + 8 && BigEndian::read_u32(&buf[header..]) & 1 != 0;
+            if has_base_offset {
+                let offset = shift(BigEndian::read_u64(&buf[pos..]));
+                BigEndian::write_u64(&mut buf[pos..], offset);
+            }
+        },
+        _ => ()
+    }
+
+    Ok(())
 }
 
-// Tag is at "moov" > "udta" > "meta" > "ilst"
-fn read_tag(moov_index: usize, atoms: &Vec<Atom>, file: &mut fs::File) -> Option<Tag> {
-    // Extract the `udta` sub-atom
-    atoms.get(moov_index).and_then(|Atom::Atom(_, _, _, udta_atoms)|
-        udta_atoms.iter().position(|Atom::Atom(_, _, name, _)| name == "udta")
-            .and_then(|udta_index| udta_atoms.get(udta_index)
+// Atoms with a length of `1` have a 64-bit length after the name
+fn atom_header_size(buf: &[u8]) -> usize {
+    if BigEndian::read_u32(buf) == 1 { 16 } else { 8 }
+}
 
-                // Extract the `meta` sub-atom
-                .and_then(|Atom::Atom(_, _, _, meta_atoms)|
-                    meta_atoms.iter().position(|Atom::Atom(_, _, name, _)| name == "meta")
-                        .and_then(|meta_index| meta_atoms.get(meta_index)
+fn update_atom_length(buf: &mut [u8], length: u64) -> Result<(), Error> {
+    if atom_header_size(buf) == 16 {
+        BigEndian::write_u64(&mut buf[8..16], length);
+
+    } else if length > u32::MAX as u64 {
+        return Err(Error::unsupported("Growing atoms past 4GB"));
+
+    } else {
+        BigEndian::write_u32(&mut buf[0..4], length as u32);
+    }
 
-                            // Extract the `ilst` sub-atom
-                            .and_then(|Atom::Atom(_, _, _, ilst_atoms)|
-                                ilst_atoms.iter().position(|Atom::Atom(_, _, name, _)| name == "ilst")
-                                    .and_then(|ilst_index| ilst_atoms.get(ilst_index)
-                                        .and_then(|Atom::Atom(_, _, _, atoms)| Tag::from_atom(atoms, file).ok())
-                                    )
-                            )
-                        )
-                )
-            )
-    )
+    Ok(())
+}
+
+fn read_atoms<R: Read + Seek>(file: &mut R) -> Vec<Atom> {
+    let mut atoms = Vec::new();
+    while let Ok(atom) = read_atom(file) {
+        atoms.push(atom);
+    }
+
+    atoms
+}
+
+// Tag is at "moov" > "udta" > "meta" > "ilst", a missing tag is treated as an empty one
+fn read_tag<R: Read + Seek>(atoms: &[Atom], file: &mut R) -> Result<Tag, Error> {
+    match find_path(atoms, &TAG_PATH) {
+        Some(chain) => Tag::from_atom(chain[chain.len() - 1].children(), file),
+        None => Ok(Tag{ items: HashMap::new() })
+    }
 }
 
 // TODO: I need someone else to comment this stuff because I don't know the formats
-fn read_atom(file: &mut fs::File) -> Result<Atom, Error> {
+fn read_atom<R: Read + Seek>(file: &mut R) -> Result<Atom, Error> {
     let mut buf: Vec<u8> = vec![0, 0, 0, 0, 0, 0, 0, 0];
-    let offset = file.seek(SeekFrom::Current(0))?;
+    let offset = file.stream_position()?;
 
     file.read_exact(&mut buf)?;
-    let mut length = BigEndian::read_u32(&buf[0..4]);
+    let name = utils::from_ascii(&buf[4..]);
+    let mut length = BigEndian::read_u32(&buf[0..4]) as u64;
 
     if length == 1 {
+        // The real length is stored as a 64-bit integer after the name
         file.read_exact(&mut buf)?;
-        length = BigEndian::read_u32(&buf[0..4]);
+        length = BigEndian::read_u64(&buf);
+
+    } else if length == 0 {
+        // The atom extends to the end of the file
+        length = file.seek(SeekFrom::End(0))? - offset;
+        file.seek(SeekFrom::Start(offset + 8))?;
     }
 
     if length < 8 {
-        return Err(Error::new(ErrorKind::InvalidData, "Mp4: Invalid Atom Size"));
+        return Err(Error::malformed(offset, &name, "Invalid atom size"));
     }
 
-    let name = utils::from_ascii(&buf[4..]);
     if name == "meta" {
         file.seek(SeekFrom::Current(4))?;
     } else if name == "stsd" {
@@ -114,32 +304,34 @@ fn read_atom(file: &mut fs::File) -> Result<Atom, Error> {
     let mut children = Vec::new();
     let containers: HashSet<&str> = [ "moov", "udta", "mdia", "meta", "ilst", "stbl", "minf", "moof", "traf", "trak", "stsd" ].iter().cloned().collect();
     if containers.contains(name.as_str()) {
-        while let Ok(atom) = read_atom(file) {
-            children.push(atom);
-
-            if let Ok(false) = file.seek(SeekFrom::Current(0)).and_then(|pos| Ok(pos < offset + length as u64)) {
-                break;
+        // Empty containers have no children, so check before reading rather than after
+        while file.stream_position()? < offset + length {
+            match read_atom(file) {
+                Ok(atom) => children.push(atom),
+                Err(_) => break
             }
         }
     }
 
-    file.seek(SeekFrom::Start(offset + length as u64))?;
-    return Ok(Atom::Atom(offset, length as u64, name, children));
+    file.seek(SeekFrom::Start(offset + length))?;
+    Ok(Atom{ offset, len: length, name, children })
 }
 
 
+#[derive(Clone)]
 pub struct Tag {
     items: HashMap<String, meta::TagData>
 }
 
 impl Tag {
-    pub(crate) fn from_atom(atoms: &Vec<Atom>, file: &mut fs::File) -> Result<Self, Error> {
+    pub(crate) fn from_atom<R: Read + Seek>(atoms: &[Atom], file: &mut R) -> Result<Self, Error> {
         let mut tag = Tag{ items: HashMap::new() };
 
-        for Atom::Atom(off, len, name, children) in atoms {
+        for Atom{ offset: off, len, name, children } in atoms {
             file.seek(SeekFrom::Start(off + 8))?;
 
             match name.as_str() {
+                // Free form items without any values have nothing worth keeping
                 "----" =>
                     match parseFreeForm(len, children, file)? {
                         (_, meta::TagData::Empty) => (),
@@ -166,118 +358,289 @@ impl Tag {
                 "covr" => { tag.items.insert("covr".to_owned(), parseCover(len, children, file)?); },
                 name => { tag.items.insert(name.to_owned(), parseString(len, children, file)?); },
             }
+
+            // Keep the raw contents of items that couldn't be parsed so they can be written back
+            if let Some(item) = tag.items.get_mut(name) {
+                if let meta::TagData::Empty = item {
+                    file.seek(SeekFrom::Start(off + 8))?;
+                    *item = parseRaw(len, children, file)?;
+                }
+            }
         }
 
         Ok(tag)
     }
+
+    // Items are keyed by atom name, or "----:mean:name" for freeform items
+    pub fn items(&self) -> &HashMap<String, meta::TagData> {
+        &self.items
+    }
+
+    // Multiple values are joined together
+    fn text(&self, name: &str) -> Option<String> {
+        match self.items.get(name) {
+            Some(meta::TagData::Str(value)) => Some(value.to_owned()),
+            Some(meta::TagData::StrList(values)) => Some(values.join(", ")),
+            _ => None
+        }
+    }
+
+    // Produce the complete `ilst` atom for the items in this tag
+    pub(crate) fn render(&self) -> Vec<u8> {
+        // Sort the items so that rendering the same tag always produces the same bytes
+        let mut names = self.items.keys().collect::<Vec<_>>();
+        names.sort();
+
+        let mut items = Vec::new();
+        for name in names {
+            items.extend(renderItem(name, &self.items[name]));
+        }
+
+        renderAtom("ilst", &items)
+    }
 }
 
-fn parseInt(len: &u64, children: &Vec<Atom>, file: &mut fs::File) -> Result<meta::TagData, Error> {
-    let buf = parseData(len, children, file, u32::MAX, false)?;
+// Data atom types (the flags field of the `data` atom)
+const TYPE_IMPLICIT: u32 = 0;
+const TYPE_UTF8: u32 = 1;
+const TYPE_UTF16: u32 = 2;
+const TYPE_INTEGER: u32 = 21;
 
-    let ret =
-        if buf.is_empty() {
-            meta::TagData::Empty
+fn renderAtom(name: &str, data: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0; 4];
+    BigEndian::write_u32(&mut buf, (data.len() + 8) as u32);
+
+    // Atom names are 4 Latin1 characters
+    buf.extend(name.chars().map(|ch| ch as u8).chain(std::iter::repeat(b' ')).take(4));
+    buf.extend_from_slice(data);
+    buf
+}
+
+// Wrap `ilst` in the given containers, outermost first
+// `meta` is a full atom and needs a handler so that players know it holds iTunes metadata
+fn renderContainers(names: &[&str], ilst: Vec<u8>) -> Vec<u8> {
+    names.iter().rev().fold(ilst, |atom, name| {
+        if *name == "meta" {
+            let mut hdlr = vec![0; 8];
+            hdlr.extend_from_slice(b"mdirappl");
+            hdlr.extend_from_slice(&[0; 9]);
+
+            let mut data = vec![0; 4];
+            data.extend(renderAtom("hdlr", &hdlr));
+            data.extend(atom);
+            renderAtom("meta", &data)
         } else {
-            meta::TagData::Uint(BigEndian::read_u16(&buf[0].1[0..2]) as u64)
-        };
-    Ok(ret)
+            renderAtom(name, &atom)
+        }
+    })
+}
+
+fn renderData(data_type: u32, data: &[u8]) -> Vec<u8> {
+    // Type, then a 4 byte locale which is always 0
+    let mut buf = vec![0; 8];
+    BigEndian::write_u32(&mut buf[0..4], data_type);
+    buf.extend_from_slice(data);
+    renderAtom("data", &buf)
+}
+
+fn renderItem(name: &str, item: &meta::TagData) -> Vec<u8> {
+    let data = match item {
+        // Free form items are stored under `----` with the `mean` and `name` in their own atoms
+        meta::TagData::Str(_) | meta::TagData::StrList(_) if name.starts_with("----:") => {
+            let mut parts = name.splitn(3, ':').skip(1);
+            let mean = parts.next().unwrap_or("");
+            let free_name = parts.next().unwrap_or("");
+
+            let mut buf = Vec::new();
+            buf.extend(renderAtom("mean", &[&[0, 0, 0, 0], mean.as_bytes()].concat()));
+            buf.extend(renderAtom("name", &[&[0, 0, 0, 0], free_name.as_bytes()].concat()));
+            buf.extend(renderStrings(item));
+            return renderAtom("----", &buf);
+        },
+
+        // `gnre` can only hold genres from the ID3v1 genre list
+        meta::TagData::Str(genre) if name == "gnre" => {
+            match meta::genre_index(genre) {
+                Some(index) => {
+                    let mut buf = vec![0; 2];
+                    BigEndian::write_u16(&mut buf, index as u16 + 1);
+                    renderData(TYPE_IMPLICIT, &buf)
+                },
+                None => return renderAtom("©gen", &renderData(TYPE_UTF8, genre.as_bytes()))
+            }
+        },
+
+        meta::TagData::Str(_) | meta::TagData::StrList(_) => renderStrings(item),
+
+        meta::TagData::Uint(value) => {
+            let size = match name {
+                "stik" | "rtng" | "akID" => 1,
+                "tmpo" => 2,
+                "plID" => 8,
+                _ => 4
+            };
+
+            let mut buf = vec![0; size];
+            BigEndian::write_uint(&mut buf, *value, size);
+            renderData(TYPE_INTEGER, &buf)
+        },
+
+        // Track numbers have 2 bytes of padding at the end, disc numbers don't
+        meta::TagData::IntPair(fst, snd) => {
+            let mut buf = vec![0; if name == "trkn" { 8 } else { 6 }];
+            BigEndian::write_u16(&mut buf[2..4], *fst as u16);
+            BigEndian::write_u16(&mut buf[4..6], *snd as u16);
+            renderData(TYPE_IMPLICIT, &buf)
+        },
+
+        meta::TagData::Bool(value) => renderData(TYPE_INTEGER, &[*value as u8]),
+
+        meta::TagData::Unimplemented(raw) if name.starts_with("----:") => return renderAtom("----", raw),
+        meta::TagData::Unimplemented(raw) => return renderAtom(name, raw),
+        meta::TagData::Empty => return Vec::new()
+    };
+
+    renderAtom(name, &data)
+}
+
+// Each value gets its own `data` atom
+fn renderStrings(item: &meta::TagData) -> Vec<u8> {
+    match item {
+        meta::TagData::Str(value) => renderData(TYPE_UTF8, value.as_bytes()),
+        meta::TagData::StrList(values) => values.iter().flat_map(|value| renderData(TYPE_UTF8, value.as_bytes())).collect(),
+        _ => Vec::new()
+    }
 }
 
-fn parseByte(len: &u64, children: &Vec<Atom>, file: &mut fs::File) -> Result<meta::TagData, Error> {
+fn parseInt<R: Read + Seek>(len: &u64, children: &[Atom], file: &mut R) -> Result<meta::TagData, Error> {
     let buf = parseData(len, children, file, u32::MAX, false)?;
 
+    // Integer items are stored in 1, 2, 4 or 8 bytes depending on the item
     let ret =
-        if buf.is_empty() {
+        if buf.is_empty() || buf[0].1.is_empty() {
             meta::TagData::Empty
         } else {
-            meta::TagData::Uint(buf[0].1[0] as u64)
+            meta::TagData::Uint(BigEndian::read_uint(&buf[0].1, min(buf[0].1.len(), 8)))
         };
-
     Ok(ret)
 }
 
-fn parseIntPair(len: &u64, children: &Vec<Atom>, file: &mut fs::File) -> Result<meta::TagData, Error> {
-    let buf = parseData(len, children, file, u32::MAX, false)?;
+fn parseByte<R: Read + Seek>(len: &u64, children: &[Atom], file: &mut R) -> Result<meta::TagData, Error> {
+    let ret = match parseFixedData(len, children, file, 1)? {
+        Some(buf) => meta::TagData::Uint(buf[0] as u64),
+        None => meta::TagData::Empty
+    };
 
-    let ret =
-        if buf.is_empty() {
-            meta::TagData::Empty
-        } else {
-            let fst = BigEndian::read_u16(&buf[0].1[2..4]);
-            let snd = BigEndian::read_u16(&buf[0].1[4..6]);
+    Ok(ret)
+}
+
+fn parseIntPair<R: Read + Seek>(len: &u64, children: &[Atom], file: &mut R) -> Result<meta::TagData, Error> {
+    let ret = match parseFixedData(len, children, file, 6)? {
+        Some(buf) => {
+            let fst = BigEndian::read_u16(&buf[2..4]);
+            let snd = BigEndian::read_u16(&buf[4..6]);
 
             meta::TagData::IntPair(fst as u32, snd as u32)
-        };
+        },
+        None => meta::TagData::Empty
+    };
 
     Ok(ret)
 }
 
-fn parseString(len: &u64, children: &Vec<Atom>, file: &mut fs::File) -> Result<meta::TagData, Error> {
+fn parseString<R: Read + Seek>(len: &u64, children: &[Atom], file: &mut R) -> Result<meta::TagData, Error> {
     let buf = parseData(len, children, file, 1, false)?;
 
     let ret =
         if buf.is_empty() {
             meta::TagData::Empty
         } else {
-            let strs = Vec::from_iter(buf
+            let strs = buf
                 .iter()
-                .map(|buf| str::from_utf8(&buf.1)
-                    .and_then(|s| Ok(s.to_owned())))
-                .filter(|r| r.is_ok())
-                .map(|r| r.unwrap_or("".to_string())))
-                .join(", ");
+                .filter_map(|buf| str::from_utf8(&buf.1).ok().map(|s| s.to_owned()))
+                .collect::<Vec<_>>();
 
-            meta::TagData::Str(strs)
+            stringData(strs)
         };
 
     Ok(ret)
 }
 
-fn parseCover(_len: &u64, _children: &Vec<Atom>, _file: &mut fs::File) -> Result<meta::TagData, Error> {
-    Ok(meta::TagData::Unimplemented)
+// Items can have several `data` atoms, each holding one value
+fn stringData(mut strs: Vec<String>) -> meta::TagData {
+    if strs.len() == 1 {
+        meta::TagData::Str(strs.remove(0))
+    } else {
+        meta::TagData::StrList(strs)
+    }
 }
 
-fn parseGenre(len: &u64, children: &Vec<Atom>, file: &mut fs::File) -> Result<meta::TagData, Error> {
-    let buf = parseData(len, children, file, u32::MAX, false)?;
+// TODO: Parse cover art, for now the atom is kept as is
+fn parseCover<R: Read + Seek>(len: &u64, children: &[Atom], file: &mut R) -> Result<meta::TagData, Error> {
+    parseRaw(len, children, file)
+}
 
-    let ret =
-        if buf.is_empty() {
-            meta::TagData::Empty
-        } else {
-            let index = BigEndian::read_u16(&buf[0].1[0..2]) as usize;
-            meta::TagData::Str(meta::GENRE_LIST.get(index - 1).unwrap_or(&"").to_string())
-        };
+fn parseRaw<R: Read + Seek>(len: &u64, _children: &[Atom], file: &mut R) -> Result<meta::TagData, Error> {
+    let mut buf = vec![0; len.saturating_sub(8) as usize];
+    file.read_exact(&mut buf)?;
+    Ok(meta::TagData::Unimplemented(buf))
+}
+
+fn parseGenre<R: Read + Seek>(len: &u64, children: &[Atom], file: &mut R) -> Result<meta::TagData, Error> {
+    let ret = match parseFixedData(len, children, file, 2)? {
+        Some(buf) => {
+            // `gnre` stores the ID3v1 genre index plus one, 0 isn't a valid genre
+            let index = BigEndian::read_u16(&buf[0..2]) as usize;
+            match index.checked_sub(1).and_then(meta::genre_name) {
+                Some(genre) => meta::TagData::Str(genre.to_string()),
+                None => meta::TagData::Empty
+            }
+        },
+        None => meta::TagData::Empty
+    };
 
     Ok(ret)
 }
 
-fn parseFreeForm(len: &u64, children: &Vec<Atom>, file: &mut fs::File) -> Result<(String, meta::TagData), Error> {
+fn parseFreeForm<R: Read + Seek>(len: &u64, children: &[Atom], file: &mut R) -> Result<(String, meta::TagData), Error> {
+    let offset = file.stream_position()? - 8;
     let buf = parseData(len, children, file, u32::MAX, true)?;
 
     if buf.len() > 2 {
-        let data_type = buf[2].0;
-        let name = format!("----:{}:{}", str::from_utf8(&buf[0].1).expect("Failed to parse string as utf-8"), str::from_utf8(&buf[1].1).expect("Failed to parse string as utf-8"));
+        let utf8 = |buf: &[u8]| str::from_utf8(buf)
+            .map(|s| s.to_owned())
+            .map_err(|_| Error::malformed(offset, "----", "Failed to parse string as utf-8"));
+        let name = format!("----:{}:{}", utf8(&buf[0].1)?, utf8(&buf[1].1)?);
 
         let mut strs = Vec::new();
-        for (_, str_buf) in &buf[2..] {
-            if data_type == 0 {
-                if let Ok(r) = str::from_utf8(str_buf).and_then(|s| Ok(s.to_owned())) {
-                    strs.push(r);
+        for (data_type, str_buf) in &buf[2..] {
+            let value = match *data_type {
+                TYPE_IMPLICIT | TYPE_UTF8 => str::from_utf8(str_buf).ok().map(|s| s.to_owned()),
+                TYPE_UTF16 if str_buf.len() % 2 == 0 => {
+                    let units = str_buf.chunks_exact(2).map(BigEndian::read_u16).collect::<Vec<_>>();
+                    String::from_utf16(&units).ok()
+                },
+                _ => None
+            };
+
+            match value {
+                Some(value) => strs.push(value),
+
+                // Anything that isn't text is kept as it was read, along with its type
+                None => {
+                    file.seek(SeekFrom::Start(offset + 8))?;
+                    return Ok((name, parseRaw(len, children, file)?));
                 }
-
-            } else {
-                strs.push(utils::from_ascii(str_buf).to_owned());
             }
         }
 
-        return Ok((name, meta::TagData::Str(strs.join(", "))));
+        return Ok((name, stringData(strs)));
     }
 
     Ok(("".to_owned(), meta::TagData::Empty))
 }
 
-fn parseBool(len: &u64, children: &Vec<Atom>, file: &mut fs::File) -> Result<meta::TagData, Error> {
+fn parseBool<R: Read + Seek>(len: &u64, children: &[Atom], file: &mut R) -> Result<meta::TagData, Error> {
     let buf = parseData(len, children, file, u32::MAX, false)?;
 
     let ret =
@@ -290,39 +653,58 @@ fn parseBool(len: &u64, children: &Vec<Atom>, file: &mut fs::File) -> Result<met
     Ok(ret)
 }
 
+// The value of the first `data` atom, which has to be at least `size` bytes long
+fn parseFixedData<R: Read + Seek>(len: &u64, children: &[Atom], file: &mut R, size: usize) -> Result<Option<Vec<u8>>, Error> {
+    let offset = file.stream_position()?;
+    let mut buf = parseData(len, children, file, u32::MAX, false)?;
+    if buf.is_empty() {
+        return Ok(None);
+    }
+
+    let (_, value) = buf.swap_remove(0);
+    if value.len() < size {
+        return Err(Error::malformed(offset, "data", &format!("Expected at least {} bytes of data, found {}", size, value.len())));
+    }
+
+    Ok(Some(value))
+}
 
-fn parseData(len: &u64, _children: &Vec<Atom>, file: &mut fs::File, expected_flags: u32, free_form: bool) -> Result<Vec<(u32, Vec<u8>)>, Error> {
+fn parseData<R: Read + Seek>(len: &u64, _children: &[Atom], file: &mut R, expected_flags: u32, free_form: bool) -> Result<Vec<(u32, Vec<u8>)>, Error> {
+    let base = file.stream_position()?;
     let mut buf = vec![0; (*len - 8) as usize];
     file.read_exact(&mut buf)?;
 
     let mut offset = 0;
-    let mut iter = 0 as u32;
+    let mut iter = 0;
     let mut ret_buf = Vec::new();
 
-    while offset < buf.len() {
+    while offset + 12 <= buf.len() {
         let length = BigEndian::read_u32(&buf[offset..(offset+4)]) as usize;
-        if length < 12 {
-            return Err(Error::new(ErrorKind::InvalidData, "Mp4 atom is too short"));
-        }
-
         let name = utils::from_ascii(&buf[(offset+4)..(offset+8)]);
         let flags = BigEndian::read_u32(&buf[(offset+8)..(offset+12)]);
+        let position = base + offset as u64;
 
         if free_form && iter < 2 {
             if iter == 0 && name != "mean" {
-                return Err(Error::new(ErrorKind::InvalidData, "Unexpected atom: Expected `mean`"));
+                return Err(Error::malformed(position, &name, "Unexpected atom: Expected `mean`"));
 
             } else if iter == 1 && name != "name" {
-                return Err(Error::new(ErrorKind::InvalidData, "Unexpected atom: Expected `name`"));
+                return Err(Error::malformed(position, &name, "Unexpected atom: Expected `name`"));
             }
 
         } else if name != "data" {
-            return Err(Error::new(ErrorKind::InvalidData, "Unexpected atom: Expected `data`"));
+            return Err(Error::malformed(position, &name, "Unexpected atom: Expected `data`"));
 
         }
 
+        // `mean` and `name` don't have the 4 byte locale that `data` has
+        let header = if free_form && iter < 2 { 12 } else { 16 };
+        if length < header || offset + length > buf.len() {
+            return Err(Error::malformed(position, &name, "Invalid atom size"));
+        }
+
         if expected_flags == u32::MAX || flags == expected_flags {
-            ret_buf.push((flags, Vec::from_iter(buf[(offset + 16)..(offset+length)].iter().cloned())));
+            ret_buf.push((flags, buf[(offset + header)..(offset+length)].to_vec()));
         }
 
         offset += length;
@@ -335,58 +717,233 @@ fn parseData(len: &u64, _children: &Vec<Atom>, file: &mut fs::File, expected_fla
 impl meta::Tag for Tag {
     fn title(&self) -> Option<String> {
         // Bug with RLS (All these methods have "two" definitions)
-        assert!(('©' as u8) == 169);
-
-        if let Some(meta::TagData::Str(title)) = self.items.get("©nam") {
-            return Some(title.to_owned());
-        }
+        assert!(('©' as u32) == 169);
 
-        None
+        self.text("©nam")
     }
     fn artist(&self) -> Option<String> {
-        if let Some(meta::TagData::Str(artist)) = self.items.get("©ART") {
-            return Some(artist.to_owned());
-        }
-
-        None
+        self.text("©ART")
     }
     fn album(&self) -> Option<String> {
-        if let Some(meta::TagData::Str(album)) = self.items.get("©alb") {
-            return Some(album.to_owned());
-        }
-
-        None
+        self.text("©alb")
     }
     fn year(&self) -> Option<u64> {
-        if let Some(meta::TagData::Uint(year)) = self.items.get("©nam") {
-            return Some(*year);
-        }
-
-        None
+        // Release dates are usually stored as "yyyy-MM-ddTHH:mm:ssZ"
+        self.text("©day").and_then(|date| date.get(0..4).and_then(|year| year.parse().ok()))
     }
     fn comment(&self) -> Option<String> {
-        if let Some(meta::TagData::Str(title)) = self.items.get("©nam") {
-            return Some(title.to_owned());
-        }
-
-        None
+        self.text("©cmt")
     }
     fn track(&self) -> Option<u32> {
-        if let Some(meta::TagData::Uint(track)) = self.items.get("trkn") {
-            return Some(*track as u32);
+        if let Some(meta::TagData::IntPair(track, _)) = self.items.get("trkn") {
+            return Some(*track);
         }
 
         None
     }
     fn genre(&self) -> Option<String> {
-        if let Some(meta::TagData::Str(genre)) = self.items.get("gnre") {
-            return Some(genre.to_owned());
+        // Custom genres are stored in `©gen`, while `gnre` holds an index into the ID3v1 genre list
+        self.text("©gen").or_else(|| self.text("gnre"))
+    }
+
+    fn set_title(&mut self, title: String) -> bool {
+        self.items.insert("©nam".to_owned(), meta::TagData::Str(title));
+        true
+    }
+    fn set_artist(&mut self, artist: String) -> bool {
+        self.items.insert("©ART".to_owned(), meta::TagData::Str(artist));
+        true
+    }
+    fn set_album(&mut self, album: String) -> bool {
+        self.items.insert("©alb".to_owned(), meta::TagData::Str(album));
+        true
+    }
+    fn set_year(&mut self, year: u64) -> bool {
+        self.items.insert("©day".to_owned(), meta::TagData::Str(year.to_string()));
+        true
+    }
+    fn set_comment(&mut self, comment: String) -> bool {
+        self.items.insert("©cmt".to_owned(), meta::TagData::Str(comment));
+        true
+    }
+    fn set_track(&mut self, track: u32) -> bool {
+        // Keep the total number of tracks if it was specified
+        let total = match self.items.get("trkn") {
+            Some(meta::TagData::IntPair(_, total)) => *total,
+            _ => 0
+        };
+
+        self.items.insert("trkn".to_owned(), meta::TagData::IntPair(track, total));
+        true
+    }
+    fn set_genre(&mut self, genre: String) -> bool {
+        self.items.remove("gnre");
+        self.items.insert("©gen".to_owned(), meta::TagData::Str(genre));
+        true
+    }
+
+    fn remove_title(&mut self) {
+        self.items.remove("©nam");
+    }
+    fn remove_artist(&mut self) {
+        self.items.remove("©ART");
+    }
+    fn remove_album(&mut self) {
+        self.items.remove("©alb");
+    }
+    fn remove_year(&mut self) {
+        self.items.remove("©day");
+    }
+    fn remove_comment(&mut self) {
+        self.items.remove("©cmt");
+    }
+    fn remove_track(&mut self) {
+        self.items.remove("trkn");
+    }
+    fn remove_genre(&mut self) {
+        self.items.remove("gnre");
+        self.items.remove("©gen");
+    }
+
+    fn properties(&self) -> meta::PropertyMap {
+        let mut properties = meta::PropertyMap::new();
+
+        for (key, name) in PROPERTY_ITEMS.iter() {
+            let values = match self.items.get(*name) {
+                Some(meta::TagData::Str(value)) => vec![value.to_owned()],
+                Some(meta::TagData::StrList(values)) => values.clone(),
+                Some(meta::TagData::Uint(value)) => vec![value.to_string()],
+                Some(meta::TagData::Bool(value)) => vec![(if *value { "1" } else { "0" }).to_owned()],
+                Some(meta::TagData::IntPair(num, 0)) => vec![num.to_string()],
+                Some(meta::TagData::IntPair(num, total)) => vec![format!("{}/{}", num, total)],
+                _ => continue
+            };
+
+            properties.insert(key.to_string(), values);
         }
 
-        None
+        if !properties.contains_key("GENRE") {
+            if let Some(genre) = self.text("gnre") {
+                properties.insert("GENRE".to_owned(), vec![genre]);
+            }
+        }
+
+        // Anything without its own item is stored as an iTunes freeform item, keyed by its name
+        for (name, item) in &self.items {
+            let key = match name.strip_prefix(FREEFORM_PREFIX) {
+                Some(_) if PROPERTY_ITEMS.iter().any(|(_, item)| item == name) => continue,
+                Some(key) => key.to_uppercase(),
+                None => continue
+            };
+            let values = match item {
+                meta::TagData::Str(value) => vec![value.to_owned()],
+                meta::TagData::StrList(values) => values.clone(),
+                _ => continue
+            };
+
+            properties.entry(key).or_insert(values);
+        }
+
+        properties
+    }
+
+    fn set_property(&mut self, key: &str, mut values: Vec<String>) -> bool {
+        let key = key.to_uppercase();
+        let name = match PROPERTY_ITEMS.iter().find(|(k, _)| *k == key) {
+            Some((_, name)) => name.to_string(),
+            None if key.is_empty() || key.chars().any(char::is_control) => return false,
+
+            // Keep the case of the existing item's name
+            None => self.items.keys()
+                .find(|name| name.strip_prefix(FREEFORM_PREFIX).is_some_and(|name| name.eq_ignore_ascii_case(&key)))
+                .map_or(format!("{}{}", FREEFORM_PREFIX, key), |name| name.to_owned())
+        };
+        let name = name.as_str();
+
+        if name == "©gen" {
+            self.items.remove("gnre");
+        }
+
+        if values.is_empty() {
+            self.items.remove(name);
+            return true;
+        }
+
+        let item = match name {
+            "trkn" | "disk" => {
+                let mut parts = values[0].split('/').map(|part| part.trim().parse::<u32>());
+                match (parts.next(), parts.next()) {
+                    (Some(Ok(num)), None) => meta::TagData::IntPair(num, 0),
+                    (Some(Ok(num)), Some(Ok(total))) => meta::TagData::IntPair(num, total),
+                    _ => return false
+                }
+            },
+            "tmpo" | "tves" | "tvsn" => match values[0].trim().parse() {
+                Ok(value) => meta::TagData::Uint(value),
+                Err(_) => return false
+            },
+            "cpil" | "pgap" | "pcst" => meta::TagData::Bool(values[0] == "1" || values[0].eq_ignore_ascii_case("true")),
+            _ if values.len() == 1 => meta::TagData::Str(values.remove(0)),
+            _ => meta::TagData::StrList(values)
+        };
+
+        self.items.insert(name.to_owned(), item);
+        true
     }
 }
 
+// Freeform items written by iTunes, properties without their own item are stored under this prefix
+const FREEFORM_PREFIX: &str = "----:com.apple.iTunes:";
+
+// Property keys and the ilst items they're stored in
+static PROPERTY_ITEMS: [(&str, &str); 45] = [
+    ("TITLE", "©nam"),
+    ("ARTIST", "©ART"),
+    ("ALBUM", "©alb"),
+    ("ALBUMARTIST", "aART"),
+    ("COMMENT", "©cmt"),
+    ("GENRE", "©gen"),
+    ("DATE", "©day"),
+    ("COMPOSER", "©wrt"),
+    ("GROUPING", "©grp"),
+    ("LYRICS", "©lyr"),
+    ("ENCODEDBY", "©too"),
+    ("COPYRIGHT", "cprt"),
+    ("TRACKNUMBER", "trkn"),
+    ("DISCNUMBER", "disk"),
+    ("COMPILATION", "cpil"),
+    ("BPM", "tmpo"),
+    ("GAPLESSPLAYBACK", "pgap"),
+    ("WORK", "©wrk"),
+    ("MOVEMENTNAME", "©mvn"),
+    ("TITLESORT", "sonm"),
+    ("ARTISTSORT", "soar"),
+    ("ALBUMSORT", "soal"),
+    ("ALBUMARTISTSORT", "soaa"),
+    ("COMPOSERSORT", "soco"),
+    ("SHOWSORT", "sosn"),
+    ("PODCAST", "pcst"),
+    ("PODCASTCATEGORY", "catg"),
+    ("PODCASTDESC", "desc"),
+    ("PODCASTID", "egid"),
+    ("PODCASTURL", "purl"),
+    ("TVSHOW", "tvsh"),
+    ("TVNETWORK", "tvnn"),
+    ("TVEPISODEID", "tven"),
+    ("TVEPISODE", "tves"),
+    ("TVSEASON", "tvsn"),
+    ("MUSICBRAINZ_TRACKID", "----:com.apple.iTunes:MusicBrainz Track Id"),
+    ("MUSICBRAINZ_ARTISTID", "----:com.apple.iTunes:MusicBrainz Artist Id"),
+    ("MUSICBRAINZ_ALBUMID", "----:com.apple.iTunes:MusicBrainz Album Id"),
+    ("MUSICBRAINZ_ALBUMARTISTID", "----:com.apple.iTunes:MusicBrainz Album Artist Id"),
+    ("MUSICBRAINZ_RELEASEGROUPID", "----:com.apple.iTunes:MusicBrainz Release Group Id"),
+    ("ASIN", "----:com.apple.iTunes:ASIN"),
+    ("LABEL", "----:com.apple.iTunes:LABEL"),
+    ("CATALOGNUMBER", "----:com.apple.iTunes:CATALOGNUMBER"),
+    ("BARCODE", "----:com.apple.iTunes:BARCODE"),
+    ("ISRC", "----:com.apple.iTunes:ISRC"),
+];
+
 pub struct _Audio {
 
 }
@@ -405,3 +962,172 @@ impl meta::Audio for _Audio {
         0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use formats::meta::Tag as MetaTag;
+
+    // A file with a single chunk, `mdat` holds "audio"
+    fn build_file(offsets: &str, udta: Option<Vec<u8>>) -> Vec<u8> {
+        let mut moov = Vec::new();
+        moov.extend(udta.unwrap_or_default());
+
+        // Chunk offsets are patched in once the position of `mdat` is known
+        let table = if offsets == "co64" { vec![0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0] } else { vec![0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0] };
+        let stbl = renderAtom("stbl", &renderAtom(offsets, &table));
+        moov.extend(renderAtom("trak", &renderAtom("mdia", &renderAtom("minf", &stbl))));
+
+        let mut data = renderAtom("ftyp", b"M4A ");
+        data.extend(renderAtom("moov", &moov));
+        let chunk = data.len() + 8;
+        data.extend(renderAtom("mdat", b"audio"));
+
+        let table_end = data.len() - 13;
+        if offsets == "co64" {
+            BigEndian::write_u64(&mut data[(table_end - 8)..], chunk as u64);
+        } else {
+            BigEndian::write_u32(&mut data[(table_end - 4)..], chunk as u32);
+        }
+        data
+    }
+
+    fn ilst(title: &str) -> Vec<u8> {
+        let mut tag = Tag{ items: HashMap::new() };
+        tag.set_title(title.to_string());
+        tag.render()
+    }
+
+    // Save the tag and return the reread file along with the bytes the chunk offset points at
+    fn save(data: &[u8], title: &str) -> (File, Vec<u8>) {
+        let mut reader = Cursor::new(data.to_vec());
+        let mut file = File::from_reader(&mut reader).unwrap();
+        file.mp4_tag_mut().set_title(title.to_string());
+
+        let mut data = data.to_vec();
+        splice_tag(&file.atoms, &mut data, file.tag.render()).unwrap();
+
+        let mut reader = Cursor::new(data.clone());
+        let file = File::from_reader(&mut reader).unwrap();
+        let mut offsets = Vec::new();
+        find_chunk_offsets(&file.atoms, &mut offsets);
+        let Atom{ offset: off, len, name, .. } = offsets[0];
+        let table = &data[(*off as usize)..((off + len) as usize)];
+        let chunk = if name == "co64" { BigEndian::read_u64(&table[16..]) } else { BigEndian::read_u32(&table[16..]) as u64 } as usize;
+
+        let audio = data[chunk..(chunk + 5)].to_vec();
+        (file, audio)
+    }
+
+    #[test]
+    fn gnre_indexes() {
+        let read = |index: u16| {
+            let ilst = renderAtom("ilst", &renderAtom("gnre", &renderData(TYPE_IMPLICIT, &index.to_be_bytes())));
+            let mut reader = Cursor::new(ilst);
+            let atoms = read_atoms(&mut reader);
+            Tag::from_atom(atoms[0].children(), &mut reader).unwrap()
+        };
+
+        assert_eq!(read(18).genre().unwrap(), "Rock");
+        assert_eq!(read(1).genre().unwrap(), "Blues");
+
+        // 0 isn't a genre, the item is kept as it was read
+        let tag = read(0);
+        assert!(tag.genre().is_none());
+        assert!(matches!(tag.items()["gnre"], meta::TagData::Unimplemented(_)));
+    }
+
+    #[test]
+    fn unknown_properties_are_freeform_items() {
+        let mut tag = Tag{ items: HashMap::new() };
+        tag.items.insert("----:com.apple.iTunes:Mood".to_owned(), meta::TagData::Str("Calm".to_owned()));
+        tag.items.insert("----:com.apple.iTunes:MusicBrainz Track Id".to_owned(), meta::TagData::Str("id".to_owned()));
+        assert_eq!(tag.properties()["MOOD"], vec!["Calm"]);
+        assert_eq!(tag.properties().len(), 2);
+
+        assert!(tag.set_property("mood", vec!["Happy".to_string()]));
+        assert!(tag.set_property("MY KEY", vec!["one".to_string(), "two".to_string()]));
+        assert!(!tag.set_property("", vec!["value".to_string()]));
+        assert!(tag.items().contains_key("----:com.apple.iTunes:MY KEY"));
+
+        let mut reader = Cursor::new(tag.render());
+        let atoms = read_atoms(&mut reader);
+        let mut tag = Tag::from_atom(atoms[0].children(), &mut reader).unwrap();
+        assert!(matches!(tag.items()["----:com.apple.iTunes:Mood"], meta::TagData::Str(ref mood) if mood == "Happy"));
+        assert_eq!(tag.properties()["MY KEY"], vec!["one", "two"]);
+
+        assert!(tag.set_property("MOOD", Vec::new()));
+        assert!(!tag.properties().contains_key("MOOD"));
+    }
+
+    #[test]
+    fn truncated_items() {
+        for (name, value) in &[("stik", &[][..]), ("trkn", &[0, 0, 0, 3][..]), ("gnre", &[1][..])] {
+            let ilst = renderAtom("ilst", &renderAtom(name, &renderData(TYPE_IMPLICIT, value)));
+            let mut reader = Cursor::new(ilst);
+            let atoms = read_atoms(&mut reader);
+
+            // The error points at the `data` atom, just past the `ilst` and item headers
+            match Tag::from_atom(atoms[0].children(), &mut reader) {
+                Err(Error::Malformed{ offset, id, .. }) => assert_eq!((offset, id.as_str()), (16, "data")),
+                _ => panic!("Truncated `{}` was accepted", name)
+            }
+        }
+    }
+
+    #[test]
+    fn binary_freeform_items() {
+        let freeform = |data_type: u32, value: &[u8]| {
+            let mut buf = renderAtom("mean", b"\0\0\0\0com.apple.iTunes");
+            buf.extend(renderAtom("name", b"\0\0\0\0KEY"));
+            buf.extend(renderData(data_type, value));
+            renderAtom("----", &buf)
+        };
+        let read = |item: &[u8]| {
+            let mut reader = Cursor::new(renderAtom("ilst", item));
+            let atoms = read_atoms(&mut reader);
+            Tag::from_atom(atoms[0].children(), &mut reader).unwrap()
+        };
+
+        let item = freeform(TYPE_INTEGER, &[0x41, 0xff, 0x00]);
+        let tag = read(&item);
+        assert!(matches!(tag.items()["----:com.apple.iTunes:KEY"], meta::TagData::Unimplemented(_)));
+        assert_eq!(tag.render(), renderAtom("ilst", &item));
+
+        let tag = read(&freeform(TYPE_UTF16, &[0, b'h', 0, b'i']));
+        assert!(matches!(tag.items()["----:com.apple.iTunes:KEY"], meta::TagData::Str(ref value) if value == "hi"));
+    }
+
+    #[test]
+    fn write_shifts_chunk_offsets() {
+        for offsets in ["stco", "co64"].iter() {
+            let mut meta = vec![0; 4];
+            meta.extend(ilst("Short"));
+            let data = build_file(offsets, Some(renderAtom("udta", &renderAtom("meta", &meta))));
+
+            let (file, audio) = save(&data, "A much longer title than before");
+            assert_eq!(file.mp4_tag().title().unwrap(), "A much longer title than before");
+            assert_eq!(audio, b"audio");
+
+            let (file, audio) = save(&data, "");
+            assert_eq!(file.mp4_tag().title().unwrap(), "");
+            assert_eq!(audio, b"audio");
+        }
+    }
+
+    #[test]
+    fn write_creates_missing_atoms() {
+        let data = build_file("stco", None);
+        let (file, audio) = save(&data, "Title");
+
+        assert_eq!(file.mp4_tag().title().unwrap(), "Title");
+        assert_eq!(audio, b"audio");
+        assert!(find_path(file.atoms(), &["moov", "udta", "meta", "hdlr"]).is_some());
+
+        let data = build_file("stco", Some(renderAtom("udta", &[])));
+        let (file, audio) = save(&data, "Title");
+        assert_eq!(file.mp4_tag().title().unwrap(), "Title");
+        assert_eq!(audio, b"audio");
+    }
+}
@@ -1,10 +1,37 @@
 
+use std::collections::BTreeMap;
 use std::rc;
 
+use super::error::Error;
+
+// Format-agnostic view of a tag, keyed by normalized names (TITLE, ALBUMARTIST, ...)
+// Every key can hold several values. See the mpeg and m4a tags for how keys map to frames and atoms
+pub type PropertyMap = BTreeMap<String, Vec<String>>;
+
 pub trait File {
     // Why can't I use `impl Trait` here ???
-    fn tag(&self) -> rc::Rc<Tag> { rc::Rc::new(NullTag{}) }
-    fn audio(&self) ->  Box<Audio> { Box::new(NullAudio{}) }
+    fn tag(&self) -> rc::Rc<dyn Tag> { rc::Rc::new(NullTag{}) }
+    fn audio(&self) ->  Box<dyn Audio> { Box::new(NullAudio{}) }
+
+    // Edits only affect tags handed out by `tag` after the edit
+    fn tag_mut(&mut self) -> Option<&mut dyn Tag> { None }
+
+    // Write the (possibly edited) tag back to the file
+    fn save(&mut self) -> Result<(), Error> {
+        Err(Error::unsupported("Saving this format"))
+    }
+
+    fn properties(&self) -> PropertyMap {
+        self.tag().properties()
+    }
+
+    // Returns the properties which couldn't be stored in the file's tag
+    fn set_properties(&mut self, properties: &PropertyMap) -> PropertyMap {
+        match self.tag_mut() {
+            Some(tag) => tag.set_properties(properties),
+            None => properties.clone()
+        }
+    }
 }
 
 pub trait Tag {
@@ -17,6 +44,43 @@ pub trait Tag {
 
     // This is an index into a defined list of "genres"
     fn genre(&self) -> Option<String> { None }
+
+    // Setters return false if the tag can't store the field
+    fn set_title(&mut self, _title: String) -> bool { false }
+    fn set_artist(&mut self, _artist: String) -> bool { false }
+    fn set_album(&mut self, _album: String) -> bool { false }
+    fn set_year(&mut self, _year: u64) -> bool { false }
+    fn set_comment(&mut self, _comment: String) -> bool { false }
+    fn set_track(&mut self, _track: u32) -> bool { false }
+    fn set_genre(&mut self, _genre: String) -> bool { false }
+
+    fn remove_title(&mut self) {}
+    fn remove_artist(&mut self) {}
+    fn remove_album(&mut self) {}
+    fn remove_year(&mut self) {}
+    fn remove_comment(&mut self) {}
+    fn remove_track(&mut self) {}
+    fn remove_genre(&mut self) {}
+
+    fn properties(&self) -> PropertyMap { PropertyMap::new() }
+
+    // Replace the values of a property, an empty list removes it
+    // Returns false if the tag has no place to store the property
+    fn set_property(&mut self, _key: &str, _values: Vec<String>) -> bool { false }
+
+    // Set every property in the map, leaving properties that aren't in the map alone
+    // Returns the properties which couldn't be stored
+    fn set_properties(&mut self, properties: &PropertyMap) -> PropertyMap {
+        let mut unsupported = PropertyMap::new();
+
+        for (key, values) in properties {
+            if !self.set_property(key, values.clone()) {
+                unsupported.insert(key.clone(), values.clone());
+            }
+        }
+
+        unsupported
+    }
 }
 
 pub trait Audio {
@@ -26,14 +90,17 @@ pub trait Audio {
     fn length(&self) -> u32 { 0 }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum TagData {
     Uint(u64),
     Str(String),
+    StrList(Vec<String>),
     Bool(bool),
     IntPair(u32, u32),
     Empty,
-    Unimplemented
+
+    // The raw contents of an item which couldn't be parsed
+    Unimplemented(Vec<u8>)
 }
 
 
@@ -50,7 +117,17 @@ impl Audio for NullAudio {}
 
 
 
-pub(crate) static GENRE_LIST: [&'static str; 192] = [
+// The genre stored as `index` in ID3v1 tags (and ID3v2 "(17)" references)
+pub fn genre_name(index: usize) -> Option<&'static str> {
+    GENRE_LIST.get(index).cloned()
+}
+
+// The ID3v1 genre byte for a genre, ignoring case
+pub fn genre_index(genre: &str) -> Option<u8> {
+    GENRE_LIST.iter().position(|g| g.eq_ignore_ascii_case(genre)).map(|index| index as u8)
+}
+
+pub(crate) static GENRE_LIST: [&str; 192] = [
     "Blues",
     "Classic Rock",
     "Country",
@@ -244,3 +321,16 @@ pub(crate) static GENRE_LIST: [&'static str; 192] = [
     "Garage Rock",
     "Psybient"
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn null_tag_rejects_fields() {
+        let mut tag = NullTag{};
+        assert!(!tag.set_title("Title".to_string()));
+        assert!(!tag.set_track(1));
+        assert!(!tag.set_property("TITLE", vec!["Title".to_string()]));
+    }
+}
@@ -1,8 +1,12 @@
 
+mod detect;
+mod error;
 mod meta;
 mod utils;
 
 pub mod m4a;
 pub mod mpeg;
 
+pub use self::detect::*;
+pub use self::error::*;
 pub use self::meta::*;
@@ -14,6 +14,12 @@ impl Audio {
     }
 }
 
+impl Default for Audio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl meta::Audio for Audio {
     fn bitrate(&self) -> u32 {
         0
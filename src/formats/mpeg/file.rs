@@ -1,36 +1,117 @@
 
+use super::super::error::Error;
 use super::super::meta;
+use super::super::utils;
 
 use std::fs;
-use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::path;
 use std::rc;
 
 use super::tag;
 
+// Padding added after the frames whenever the tag has to be moved, so later edits can happen in place
+const DEFAULT_PADDING: usize = 1024;
+
 pub struct File {
     tag: rc::Rc<tag::Tag>,
+
+    // Files read from other sources can't be saved
+    path: Option<path::PathBuf>,
 }
 
 impl File {
     #[allow(dead_code)]
     pub fn open<P: AsRef<path::Path>>(path: P) -> Result<Self, Error> {
-        let mut file = fs::File::open(path)?;
+        let mut file = fs::File::open(&path)?;
 
+        let mut ret = Self::from_reader(&mut file)?;
+        ret.path = Some(path.as_ref().to_path_buf());
+        Ok(ret)
+    }
+
+    pub fn from_reader<R: Read + Seek>(reader: &mut R) -> Result<Self, Error> {
         use self::Id3Version::*;
         let mut tags = Vec::new();
-        for (tag_type, location) in find_mpeg_tags(&mut file)? {
+        for (tag_type, location) in find_mpeg_tags(reader)? {
             tags.push(match tag_type {
-                ID3v2 => rc::Rc::new(tag::Tag::id3v2_from_file(&mut file, location)?),
-                ID3v1 => rc::Rc::new(tag::Tag::id3v1_from_file(&mut file, location)?),
-                APE => rc::Rc::new(tag::Tag::default()),
+                ID3v2 => rc::Rc::new(tag::Tag::id3v2_from_file(reader, location)?),
+                ID3v1 => rc::Rc::new(tag::Tag::id3v1_from_file(reader, location)?),
+                Ape => rc::Rc::new(tag::Tag::default()),
             });
         }
 
-        if tags.len() == 0 {
-            Err(Error::new(ErrorKind::Other, "Non-id3v2 tags are not supported"))
-        } else {
-            Ok(File{ tag: rc::Rc::new(tag::Tag::unify(tags)) })
+        // Untagged files start out with an empty tag
+        Ok(File{ tag: rc::Rc::new(tag::Tag::unify(tags)), path: None })
+    }
+
+    fn open_for_writing(&self) -> Result<fs::File, Error> {
+        match self.path {
+            Some(ref path) => Ok(fs::OpenOptions::new().read(true).write(true).open(path)?),
+            None => Err(Error::unsupported("Saving files which weren't opened from a path"))
+        }
+    }
+
+    // Replace the ID3v2 tag at the front of the file with the current tag, written as ID3v2.`version`
+    // The audio frames (and any trailing tags) are left untouched
+    pub fn write_id3v2(&self, version: u8) -> Result<(), Error> {
+        let mut file = self.open_for_writing()?;
+        let existing_size = id3v2_size(&mut file)? as usize;
+
+        // Reuse the space taken by the old tag if the new one fits, so the audio doesn't have to move
+        let tag = self.tag.render(version, 0)?;
+        if tag.len() <= existing_size {
+            let tag = self.tag.render(version, existing_size - tag.len())?;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&tag)?;
+            return Ok(());
+        }
+
+        // Otherwise the audio has to move, which is done in a new file so a failure can't damage the original
+        let tag = self.tag.render(version, DEFAULT_PADDING)?;
+        drop(file);
+
+        let path = self.path.as_ref().expect("files opened for writing have a path");
+        utils::replace_file(path, |temp| {
+            let mut file = fs::File::open(path)?;
+            file.seek(SeekFrom::Start(existing_size as u64))?;
+
+            temp.write_all(&tag)?;
+            io::copy(&mut file, temp)?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    // The unified tag, with access to the individual ID3v2 frames
+    pub fn mpeg_tag(&self) -> &tag::Tag {
+        &self.tag
+    }
+
+    pub fn mpeg_tag_mut(&mut self) -> &mut tag::Tag {
+        rc::Rc::make_mut(&mut self.tag)
+    }
+
+    // Write the current tag as an ID3v1.1 block, replacing the existing block if there is one
+    pub fn write_id3v1(&self) -> Result<(), Error> {
+        let mut file = self.open_for_writing()?;
+
+        match find_id3v1(&mut file)? {
+            Some(location) => file.seek(SeekFrom::Start(location))?,
+            None => file.seek(SeekFrom::End(0))?
+        };
+
+        file.write_all(&self.tag.render_id3v1())?;
+        Ok(())
+    }
+
+    // Remove the ID3v1 block from the end of the file, if there is one
+    pub fn strip_id3v1(&self) -> Result<(), Error> {
+        let mut file = self.open_for_writing()?;
+
+        match find_id3v1(&mut file)? {
+            Some(location) => Ok(file.set_len(location)?),
+            None => Ok(())
         }
     }
 }
@@ -38,16 +119,33 @@ impl File {
 enum Id3Version {
     ID3v2,
     ID3v1,
-    APE
+    Ape
 }
 
 impl meta::File for File {
-    fn tag(&self) -> rc::Rc<meta::Tag> {
+    fn tag(&self) -> rc::Rc<dyn meta::Tag> {
         self.tag.clone()
     }
+
+    fn tag_mut(&mut self) -> Option<&mut dyn meta::Tag> {
+        let tag: &mut tag::Tag = rc::Rc::make_mut(&mut self.tag);
+        Some(tag)
+    }
+
+    // Existing ID3v1 tags are updated as well so that the two don't disagree
+    fn save(&mut self) -> Result<(), Error> {
+        self.write_id3v2(4)?;
+
+        let mut file = self.open_for_writing()?;
+        if find_id3v1(&mut file)?.is_some() {
+            self.write_id3v1()?;
+        }
+
+        Ok(())
+    }
 }
 
-fn find_mpeg_tags(file: &mut fs::File) -> Result<Vec<(Id3Version, u64)>, Error> {
+fn find_mpeg_tags<R: Read + Seek>(file: &mut R) -> Result<Vec<(Id3Version, u64)>, Error> {
     let mut tags = Vec::new();
 
     if let Some(location) = find_id3v2(file)? {
@@ -59,19 +157,15 @@ fn find_mpeg_tags(file: &mut fs::File) -> Result<Vec<(Id3Version, u64)>, Error>
     }
 
     if let Some(location) = find_ape(file)? {
-        tags.push((Id3Version::APE, location));
+        tags.push((Id3Version::Ape, location));
     }
 
-    if tags.len() == 0 {
-        Err(Error::new(ErrorKind::InvalidData, "Could not find mpeg tag location"))
-    } else {
-        Ok(tags)
-    }
+    Ok(tags)
 }
 
-fn find_id3v2(file: &mut fs::File) -> Result<Option<u64>, Error> {
-    let header_id = vec!['I' as u8, 'D' as u8, '3' as u8];
-    let mut buf = vec![0 as u8; header_id.len()];
+fn find_id3v2<R: Read + Seek>(file: &mut R) -> Result<Option<u64>, Error> {
+    let header_id = vec![b'I', b'D', b'3'];
+    let mut buf = vec![0_u8; header_id.len()];
 
     file.seek(SeekFrom::Start(0))?;
     file.read_exact(&mut buf)?;
@@ -84,7 +178,7 @@ fn find_id3v2(file: &mut fs::File) -> Result<Option<u64>, Error> {
         return Ok(None)
     }
 
-    Err(Error::new(ErrorKind::InvalidData, "ID3v2 tag possibly not at front of file"))
+    Err(Error::malformed(0, "ID3", "ID3v2 tag possibly not at front of file"))
     // const long tagOffset = find(headerID);
     // if(tagOffset < 0)
     //     return -1;
@@ -96,11 +190,34 @@ fn find_id3v2(file: &mut fs::File) -> Result<Option<u64>, Error> {
     // return tagOffset;
 }
 
-fn find_id3v1(file: &mut fs::File) -> Result<Option<u64>, Error> {
+// Number of bytes taken up by an ID3v2 tag at the start of the file (0 if there is no tag)
+fn id3v2_size(file: &mut fs::File) -> Result<u64, Error> {
+    let mut buf = vec![0; 10];
+
+    file.seek(SeekFrom::Start(0))?;
+    match file.read_exact(&mut buf) {
+        Ok(()) => (),
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+        Err(err) => return Err(err.into())
+    }
+
+    if &buf[0..3] != b"ID3" {
+        return Ok(0);
+    }
+
+    Ok(tag::parse_tag_header(&buf)?.total_size())
+}
+
+fn find_id3v1<R: Read + Seek>(file: &mut R) -> Result<Option<u64>, Error> {
+    // Files too small to hold an ID3v1 tag can't have one
+    if file.seek(SeekFrom::End(0))? < 128 {
+        return Ok(None);
+    }
+
     let loc = file.seek(SeekFrom::End(-128))?;
 
-    let header_id = vec!['T' as u8, 'A' as u8, 'G' as u8];
-    let mut buf = vec![0 as u8; header_id.len()];
+    let header_id = vec![b'T', b'A', b'G'];
+    let mut buf = vec![0_u8; header_id.len()];
 
     file.read_exact(&mut buf)?;
     if buf == header_id {
@@ -110,6 +227,130 @@ fn find_id3v1(file: &mut fs::File) -> Result<Option<u64>, Error> {
     Ok(None)
 }
 
-fn find_ape(_file: &mut fs::File) -> Result<Option<u64>, Error> {
+fn find_ape<R: Read + Seek>(_file: &mut R) -> Result<Option<u64>, Error> {
     Ok(None)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use formats::meta::{File as MetaFile, Tag as MetaTag};
+
+    // A file in the temporary directory, removed when dropped
+    struct TempFile(path::PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &[u8]) -> Self {
+            let path = std::env::temp_dir().join(format!("tags-{}-{}.mp3", std::process::id(), name));
+            fs::write(&path, contents).unwrap();
+            TempFile(path)
+        }
+
+        fn contents(&self) -> Vec<u8> {
+            fs::read(&self.0).unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn audio() -> Vec<u8> {
+        let mut audio = vec![0xff, 0xfb, 0x90, 0x64];
+        audio.resize(417, 0x55);
+        audio
+    }
+
+    #[test]
+    fn id3v2_round_trip() {
+        let temp = TempFile::new("id3v2", &audio());
+
+        let mut file = File::open(&temp.0).unwrap();
+        assert!(file.tag().title().is_none());
+        file.tag_mut().unwrap().set_title("Title".to_string());
+        file.tag_mut().unwrap().set_artist("Artist".to_string());
+        file.save().unwrap();
+
+        let contents = temp.contents();
+        assert!(contents.ends_with(&audio()));
+        let size = contents.len();
+
+        // A smaller tag is written into the existing space
+        let mut file = File::open(&temp.0).unwrap();
+        assert_eq!(file.tag().title().unwrap(), "Title");
+        assert_eq!(file.tag().artist().unwrap(), "Artist");
+        file.tag_mut().unwrap().remove_artist();
+        file.save().unwrap();
+        assert_eq!(temp.contents().len(), size);
+
+        // A tag which doesn't fit moves the audio
+        let mut file = File::open(&temp.0).unwrap();
+        assert!(file.tag().artist().is_none());
+        file.tag_mut().unwrap().set_comment("a".repeat(2000));
+        file.save().unwrap();
+
+        let contents = temp.contents();
+        assert!(contents.len() > size);
+        assert!(contents.ends_with(&audio()));
+
+        let file = File::open(&temp.0).unwrap();
+        assert_eq!(file.tag().title().unwrap(), "Title");
+        assert_eq!(file.tag().comment().unwrap(), "a".repeat(2000));
+
+        // v2.4 tags may end with a footer, which isn't counted in the tag size
+        let mut tag = tag::Tag::default();
+        tag.set_album("Album".to_string());
+        tag.set_title("Hi".to_string());
+        let mut contents = tag.render(4, 0).unwrap();
+
+        // The footer is a copy of the header with "3DI" as the identifier
+        contents[5] |= 0b10000;
+        let mut footer = contents[..10].to_vec();
+        footer[..3].copy_from_slice(b"3DI");
+        contents.extend(footer);
+        contents.extend(audio());
+        let temp = TempFile::new("id3v2-footer", &contents);
+
+        let mut file = File::open(&temp.0).unwrap();
+        assert_eq!(file.tag().album().unwrap(), "Album");
+        assert_eq!(file.tag().title().unwrap(), "Hi");
+        file.tag_mut().unwrap().set_artist("Artist".to_string());
+        file.save().unwrap();
+
+        let file = File::open(&temp.0).unwrap();
+        assert_eq!(file.tag().album().unwrap(), "Album");
+        assert_eq!(file.tag().title().unwrap(), "Hi");
+        assert_eq!(file.tag().artist().unwrap(), "Artist");
+        assert!(temp.contents().ends_with(&audio()));
+    }
+
+    #[test]
+    fn id3v1_round_trip() {
+        let temp = TempFile::new("id3v1", &audio());
+
+        let mut file = File::open(&temp.0).unwrap();
+        file.tag_mut().unwrap().set_title("A title that is longer than thirty characters".to_string());
+        file.tag_mut().unwrap().set_track(7);
+        file.tag_mut().unwrap().set_genre("Rock".to_string());
+        file.write_id3v1().unwrap();
+
+        let contents = temp.contents();
+        assert_eq!(contents.len(), audio().len() + 128);
+        assert_eq!(&contents[audio().len()..][..3], b"TAG");
+
+        let mut reader = io::Cursor::new(contents[audio().len()..].to_vec());
+        let tag = tag::Tag::id3v1_from_file(&mut reader, 0).unwrap();
+        assert_eq!(tag.title().unwrap(), "A title that is longer than th");
+        assert_eq!(tag.track().unwrap(), 7);
+        assert_eq!(tag.genre().unwrap(), "Rock");
+
+        // Writing again replaces the block instead of adding another
+        file.write_id3v1().unwrap();
+        assert_eq!(temp.contents().len(), audio().len() + 128);
+
+        file.strip_id3v1().unwrap();
+        assert_eq!(temp.contents(), audio());
+    }
+}
@@ -1,41 +1,106 @@
 
 use super::tag;
+use formats::error::Error;
+use formats::meta;
 use formats::utils;
 
 use std::cmp::min;
 use std::convert;
-use std::io::{Error, ErrorKind};
+use std::io::{Read, Write};
+use std::ops;
 use std::str;
 
-use byteorder::{BigEndian, ByteOrder};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
 
-pub(crate) struct Frame {
-    pub size: usize,
-    pub frame_id: String,
-    pub sub: SubClass
+// A single ID3v2 frame
+// Frames read from v2.2 and v2.3 tags are given their v2.4 ids
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub(crate) size: usize,
+    pub(crate) frame_id: String,
+    pub(crate) flags: FrameFlags,
+    pub(crate) group_id: Option<u8>,
+    pub(crate) data: Vec<u8>,
+    pub(crate) sub: SubClass,
+
+    // Version of the tag the frame was read from
+    pub(crate) version: u8
+}
+
+// The status and format flags from the frame header
+#[derive(Clone, Debug, Default)]
+pub struct FrameFlags {
+    pub tag_alter_preservation: bool,
+    pub file_alter_preservation: bool,
+    pub read_only: bool,
+    pub grouping_ident: bool,
+    pub compression: bool,
+    pub encryption: bool,
+    pub unsynch: bool,
+    pub data_length_indicator: bool
 }
 
 impl Frame {
+    // Frames created in memory are given the payload they'd be written with in a v2.4 tag
+    pub fn new(frame_id: &str, sub: SubClass) -> Self {
+        let data = render_payload(frame_id, &sub, 4).unwrap_or_default();
+
+        Self{
+            size: data.len(),
+            frame_id: frame_id.to_string(),
+            flags: FrameFlags::default(),
+            group_id: None,
+            data,
+            sub,
+            version: 4
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.frame_id
+    }
+
+    pub fn flags(&self) -> &FrameFlags {
+        &self.flags
+    }
+
+    pub fn group_id(&self) -> Option<u8> {
+        self.group_id
+    }
+
+    // The frame body, after removing unsynchronisation and the data length indicator
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    // The decoded contents of the frame
+    pub fn content(&self) -> &SubClass {
+        &self.sub
+    }
+
     fn get_header(buf: &mut [u8], version: u8) -> Result<Header, Error> {
         let mut header = Header::default(version);
 
         if version < 3 {
             if buf.len() < 3 {
-                return Err(Error::new(ErrorKind::Other, "Frame ID not specified"));
+                return Err(Error::malformed(0, "", "Frame ID not specified"));
             }
 
             header.frame_id = utils::from_ascii(&buf[0..3]);
             header.size = if buf.len() < 6 {
                 0
             } else {
-                let tmp = [buf[3], buf[4], buf[5], 0];
+                let tmp = [0, buf[3], buf[4], buf[5]];
                 BigEndian::read_u32(&tmp) as u64
             }
 
 
         } else if version == 3 {
             if buf.len() < 4 {
-                return Err(Error::new(ErrorKind::Other, "Frame ID not specified"));
+                return Err(Error::malformed(0, "", "Frame ID not specified"));
             }
 
             header.frame_id = utils::from_ascii(&buf[0..4]);
@@ -53,7 +118,7 @@ impl Frame {
 
         } else {
             if buf.len() < 4 {
-                return Err(Error::new(ErrorKind::Other, "Frame ID not specified"));
+                return Err(Error::malformed(0, "", "Frame ID not specified"));
             }
 
             header.frame_id = utils::from_ascii(&buf[0..4]);
@@ -97,7 +162,7 @@ impl Frame {
             || frame_header.size <= (if frame_header.data_length_indicator { 4 } else { 0 })
             || frame_header.size as usize > buf.len()
         {
-            // return Err(Error::new(ErrorKind::InvalidData, "Invalid frame length"));
+            // return Err(Error::malformed(0, &frame_header.frame_id, "Invalid frame length"));
             return Ok(None);
         }
 
@@ -109,178 +174,418 @@ impl Frame {
         }
 
         for ch in frame_header.frame_id.chars() {
-            if (ch < 'A' || ch > 'Z') && (ch < '0' || ch > '9') {
-                return Err(Error::new(ErrorKind::InvalidData, "Frame ID was not 4 uppercase Latin1 Letters"));
+            if !ch.is_ascii_uppercase() && !ch.is_ascii_digit() {
+                return Err(Error::malformed(0, &frame_header.frame_id, "Frame ID was not 4 uppercase Latin1 Letters"));
             }
         }
 
-        if version > 3 && (header.unsynch || frame_header.unsynch) {
-            let size = sizeof_frame_header(header.major_version) as usize;
-            let tmp = tag::synch::decode_slice(&buf[size..(size + header.size as usize)]);
-            for i in 0..tmp.len() {
-                let i = i as usize;
-                buf[size + i] = tmp[i];
-            }
+        if frame_header.encryption {
+            return Err(Error::unsupported("Encrypted frames"));
         }
 
-        if frame_header.compression {
-            return Err(Error::new(ErrorKind::Other, "Compressed frames not currently supported"));
+        let fields = Frame::field_data(buf, &frame_header)?;
+        let offset = fields.data.start;
+        let mut data = buf[fields.data].to_vec();
+        if version > 3 && (header.unsynch || frame_header.unsynch) {
+            data = tag::synch::decode_slice(&data);
         }
 
-        if frame_header.encryption {
-            return Err(Error::new(ErrorKind::Other, "Encrypted frames not currently supported"));
+        // Unsynchronisation is applied after compression, so it has to be undone first
+        if frame_header.compression {
+            let size = fields.data_length.unwrap_or(0);
+            data = inflate(&data, size).ok_or_else(|| Error::malformed(offset as u64, &frame_header.frame_id, "Compressed frame data could not be inflated"))?;
+            if data.len() != size as usize {
+                return Err(Error::malformed(offset as u64, &frame_header.frame_id, "Compressed frame data didn't match its decompressed size"));
+            }
         }
 
+        // Frames which can't be converted to v2.4 are kept as they are
+        let known = frame_header.update(version);
         let mut frame = Frame{
             size: frame_header.size as usize,
             frame_id: frame_header.frame_id.clone(),
-            sub: SubClass::Unknown
+            flags: frame_header.flags(),
+            group_id: fields.group_id,
+            data,
+            sub: SubClass::Unknown,
+            version
         };
-        if !frame_header.update(version) {
-            return Ok(Some(frame))
+        if known {
+            frame.sub = parse_content(&frame.frame_id, &frame.data, header);
         }
 
-        // Extract the frame subclass information
-        let first_char = frame_header.frame_id.chars().next().unwrap_or('\0');
-        frame.sub = match frame_header.frame_id.as_str() {
-            // Text frames
-            tag if first_char == 'T' || tag == "WFED" || tag == "MVNM" || tag == "MVIN" => {
-                let data = Frame::field_data(buf, &frame_header)?;
+        if frame.frame_id == PASSTHROUGH_ID {
+            frame.unwrap_passthrough(header);
+        }
 
-                if data.len() < 2 {
-                    SubClass::Text("".to_string(), StringType::UTF16)
+        // RVAD is replaced by RVA2 in v2.4, it's converted back when writing v2.3 tags
+        if let SubClass::RelativeVolume(_) = frame.sub {
+            frame.frame_id = "RVA2".to_string();
+        }
 
-                } else {
-                    let encoding = StringType::from(data[0]);
-                    let alignment = match encoding {
-                        StringType::Latin1 | StringType::UTF8 => 1,
-                        _ => 2
-                    };
+        Ok(Some(frame))
+    }
 
-                    let mut len = data.len() - 1;
+    // Splits the group id off of the frame body
+    fn field_data(buf: &[u8], header: &Header) -> Result<FieldData, Error> {
+        let header_size = sizeof_frame_header(header.version) as usize;
+        let end = min(buf.len(), header_size + header.size as usize);
+        let mut offset = header_size;
+        let mut group_id = None;
+        let mut size = None;
 
-                    while len > 0 && data[len] == 0 {
-                        len -= 1;
-                    }
+        // v2.4 puts the group id before the data length indicator, v2.3 puts it after the decompressed size
+        if header.version > 3 && header.grouping_ident {
+            group_id = buf.get(offset).cloned();
+            offset += 1;
+        }
 
-                    while len % alignment != 0 {
-                        len += 1;
-                    }
+        if header.compression || header.data_length_indicator {
+            size = match buf.get(offset..(offset + 4)) {
+                Some(size) if header.version > 3 => Some(tag::synch::int_from_buf(size)),
+                Some(size) => Some(BigEndian::read_u32(size)),
+                None => return Err(Error::malformed(offset as u64, &header.frame_id, "Frame too short for its data length indicator"))
+            };
+            offset += 4;
+        }
 
-                    // TODO: I don't split the data based on a text delimeter
-                    // taglib:textidentificationframe.cpp:211
-
-                    let end = min(len + 1, data.len());
-                    let text = match encoding {
-                        StringType::Latin1 => utils::from_ascii(&data[1..end]),
-
-
-                        // TODO: Fix errors in extract of utf16 strings (every other character is chinese, I think only half the string is there)
-                            // NOTE: It also seems like the "tag size" field indicates the number of characters, not the number of bytes (though I don't have anything to prove this)
-                            // NOTE: We can somewhat deal with this by using the ID3v1 tags, but it's not a perfect solution
-                        StringType::UTF16 | StringType::UTF16be | StringType::UTF16le => {
-                            let mut utf16_buf = Vec::new();
-                            let buf = &data[1..end];
-
-                            let swap = buf[0] == 0xff && buf[1] == 0xfe;
-                            for i in 1..(buf.len() / 2) {
-                                let val = if swap {
-                                        let fst_byte = (buf[i+1] as u16) & 0xff;
-                                        let snd_byte = (buf[i] as u16) & 0xff;
-                                        (fst_byte << 8) | snd_byte
-                                    } else {
-                                        let fst_byte = (buf[i] as u16) & 0xff;
-                                        let snd_byte = (buf[i+1] as u16) & 0xff;
-                                        (fst_byte << 8) | snd_byte
-                                    };
-
-                                utf16_buf.push(val);
-                            }
-
-                            match String::from_utf16(&utf16_buf) {
-                                Ok(s) => s,
-                                Err(_) => return Err(Error::new(ErrorKind::InvalidData, "Failed to convert string from utf16"))
-                            }
-                        },
-                        _ => match str::from_utf8(&data[1..end]) {
-                            Ok(s) => s.to_string(),
-                            Err(_) => return Err(Error::new(ErrorKind::InvalidData, "Failed to convert string from utf8"))
-                        }
-                    };
-
-                    SubClass::Text(text, encoding)
-                }
-            },
+        if header.version == 3 && header.grouping_ident {
+            group_id = buf.get(offset).cloned();
+            offset += 1;
+        }
 
-            // Comments
-            "COMM" => SubClass::Unknown,
+        Ok(FieldData{ group_id, data_length: size, data: min(offset, end)..end })
+    }
 
-            // Picture
-            "APIC" => SubClass::Unknown,
-            "PIC" => SubClass::Unknown,
+    // Produces the full frame (header and body) for writing into a v2.3 or v2.4 tag
+    // The body is compressed if `compress` is set and it actually ends up smaller
+    // Returns `None` if the frame can't be represented in the requested version
+    pub(crate) fn render(&self, version: u8, compress: bool) -> Option<Vec<u8>> {
+        if version > 3 && V23_ONLY_FRAMES.contains(&self.frame_id.as_str()) {
+            return None;
+        }
 
-            // Relative Volume Adjustment
-            "RVA2" => SubClass::Unknown,
+        let frame_id = match self.sub {
+            SubClass::RelativeVolume(_) if version == 3 => Some("RVAD"),
+            _ => downgrade_frame_id(&self.frame_id, version)
+        };
 
-            // Unique File Identifier
-            "UFID" => SubClass::Unknown,
+        let (frame_id, data) = match (frame_id, &self.sub) {
+            (Some(frame_id), SubClass::Unknown) if self.writable_as(frame_id, version) => (frame_id, self.data.clone()),
+            (Some(frame_id), sub) if frame_id.len() == 4 && !matches!(sub, SubClass::Unknown) => (frame_id, render_payload(frame_id, sub, version)?),
 
-            // General Encapsulated Object
-            "GEOB" => SubClass::Unknown,
+            // Frames that don't exist in the requested version are kept in an experimental frame
+            _ => (PASSTHROUGH_ID, self.render_passthrough()?)
+        };
 
-            // URL
-            _url if first_char == 'W' => SubClass::Unknown,
+        Some(self.render_frame(frame_id, data, version, compress))
+    }
 
-            // Lyrics
-            "USLT" => SubClass::Unknown,
-            "SYLT" => SubClass::Unknown,
+    // Produces the frame wrapped in an experimental frame, for frames that can only appear once in the requested version
+    pub(crate) fn render_wrapped(&self, version: u8, compress: bool) -> Option<Vec<u8>> {
+        Some(self.render_frame(PASSTHROUGH_ID, self.render_passthrough()?, version, compress))
+    }
 
-            // Event timing
-            "ETCO" => SubClass::Unknown,
+    fn render_frame(&self, frame_id: &str, data: Vec<u8>, version: u8, compress: bool) -> Vec<u8> {
+        let compressed = if compress {
+            deflate(&data).filter(|compressed| compressed.len() + 4 < data.len())
+        } else {
+            None
+        };
+        let is_compressed = compressed.is_some();
 
-            // Popularimeter
-            "POPM" => SubClass::Unknown,
+        // v2.4 puts the group id before the data length indicator, v2.3 puts it after the decompressed size
+        let mut body = Vec::with_capacity(data.len() + 5);
+        if version > 3 {
+            body.extend(self.group_id);
+        }
+        if is_compressed {
+            let mut size = [0; 4];
+            if version == 3 {
+                BigEndian::write_u32(&mut size, data.len() as u32);
+            } else {
+                size = tag::synch::int_to_buf(data.len() as u32);
+            }
+            body.extend_from_slice(&size);
+        }
+        if version == 3 {
+            body.extend(self.group_id);
+        }
+        body.extend(compressed.unwrap_or(data));
+        let data = body;
 
-            // Private
-            "PRIV" => SubClass::Unknown,
+        let mut buf = Vec::with_capacity(data.len() + 10);
+        buf.extend_from_slice(frame_id.as_bytes());
 
-            // Ownership
-            "OWNE" => SubClass::Unknown,
+        let mut size = [0; 4];
+        if version == 3 {
+            BigEndian::write_u32(&mut size, data.len() as u32);
+        } else {
+            size = tag::synch::int_to_buf(data.len() as u32);
+        }
+        buf.extend_from_slice(&size);
 
-            // Chapter
-            "CHAP" => SubClass::Unknown,
+        buf.extend_from_slice(&self.render_flags(version, is_compressed));
+        buf.extend(data);
+        buf
+    }
 
-            // Table of Contents
-            "CTOC" => SubClass::Unknown,
+    // Frames that weren't parsed are written back exactly as they were read, as long as the id means
+    // the same thing in the requested version
+    fn writable_as(&self, frame_id: &str, version: u8) -> bool {
+        match self.version {
+            _ if frame_id.len() != 4 => false,
 
-            // Podcast
-            "PCST" => SubClass::Unknown,
+            // v2.2 pictures store a 3 character image format instead of a MIME type
+            2 => frame_id != "APIC",
+            3 => version == 3 || !V23_ONLY_FRAMES.contains(&frame_id),
+            _ => true
+        }
+    }
 
-            // Unknown
-            _ => SubClass::Unknown
+    // The original frame id, the version it was read from and the frame body
+    // Parsed frames are stored with their ID3v2.4 body
+    fn render_passthrough(&self) -> Option<Vec<u8>> {
+        let (version, data) = match self.sub {
+            SubClass::Unknown => (self.version, self.data.clone()),
+            ref sub => (4, render_payload(&self.frame_id, sub, 4)?)
         };
 
-        Ok(Some(frame))
+        let mut buf = self.frame_id.as_bytes().to_vec();
+        buf.push(0);
+        buf.push(version);
+        buf.extend(data);
+        Some(buf)
     }
 
-    fn field_data(buf: &[u8], header: &Header) -> Result<Vec<u8>, Error> {
-        let header_size = sizeof_frame_header(header.version) as usize;
+    // Restore a frame written by `render_passthrough`, other programs' frames are left alone
+    fn unwrap_passthrough(&mut self, header: &tag::TagHeader) {
+        let end = match self.data.iter().position(|byte| *byte == 0) {
+            Some(end) if end + 1 < self.data.len() => end,
+            _ => return
+        };
 
-        let mut offset = header_size;
-        let mut len = header.size as usize;
+        let frame_id = &self.data[..end];
+        let version = self.data[end + 1];
+        let valid_id = frame_id.iter().all(|byte| byte.is_ascii_uppercase() || byte.is_ascii_digit());
+        if !(3..=4).contains(&frame_id.len()) || !valid_id || !(2..=4).contains(&version) {
+            return;
+        }
 
-        if header.compression || header.data_length_indicator {
-            len = tag::synch::int_from_buf(&buf[header_size..(header_size+4)]) as usize;
-            offset += 4;
+        self.frame_id = frame_id.iter().map(|byte| *byte as char).collect();
+        self.data = self.data[(end + 2)..].to_vec();
+        self.version = version;
+
+        if self.frame_id.len() == 4 && (version != 3 || parsed_in_v23(&self.frame_id)) {
+            let header = tag::TagHeader{ major_version: version, unsynch: false, ..header.clone() };
+            self.sub = parse_content(&self.frame_id, &self.data, &header);
         }
+    }
 
-        if header.compression && !header.encryption {
-            return Err(Error::new(ErrorKind::Other, "Compressed frames not currently supported"));
+    // The frame is always written unencrypted and without unsynchronisation
+    // Compressed v2.4 frames need the data length indicator as well
+    fn render_flags(&self, version: u8, compressed: bool) -> [u8; 2] {
+        let flags = &self.flags;
+        let grouping = self.group_id.is_some();
+
+        if version == 3 {
+            [
+                (flags.tag_alter_preservation as u8) << 7 | (flags.file_alter_preservation as u8) << 6 | (flags.read_only as u8) << 5,
+                (compressed as u8) << 7 | (grouping as u8) << 5
+            ]
+        } else {
+            [
+                (flags.tag_alter_preservation as u8) << 6 | (flags.file_alter_preservation as u8) << 5 | (flags.read_only as u8) << 4,
+                (grouping as u8) << 6 | (compressed as u8) << 3 | (compressed as u8)
+            ]
         }
+    }
+}
+
+// Decode the body of a frame, given its (v2.4) frame id
+// Frames that can't be decoded are left as `SubClass::Unknown`
+fn parse_content(frame_id: &str, data: &[u8], header: &tag::TagHeader) -> SubClass {
+    let version = header.major_version;
+    let first_char = frame_id.chars().next().unwrap_or('\0');
+    match frame_id {
+        // User defined text
+        "TXXX" => UserText::parse(data).map_or(SubClass::Unknown, SubClass::UserText),
+
+        // Text frames
+        // ID3v2.3 values separated with '/' are kept as they are, since '/' may also be part of a value (eg. "AC/DC")
+        tag if first_char == 'T' || tag == "WFED" || tag == "MVNM" || tag == "MVIN" => {
+            if data.len() < 2 {
+                SubClass::Text(Vec::new(), StringType::UTF16)
+            } else {
+                let encoding = StringType::from(data[0]);
+                let values = split_values(&data[1..], &encoding);
+                SubClass::Text(values, encoding)
+            }
+        },
+
+        // Comments
+        "COMM" => Comment::parse(data).map_or(SubClass::Unknown, SubClass::Comment),
+
+        // Picture
+        // v2.2 "PIC" frames have been renamed by now
+        "APIC" => Picture::parse(data, version).map_or(SubClass::Unknown, SubClass::Picture),
+
+        // Relative Volume Adjustment
+        "RVA2" => RelativeVolume::parse(data).map_or(SubClass::Unknown, SubClass::RelativeVolume),
+        "RVAD" => RelativeVolume::parse_rvad(data).map_or(SubClass::Unknown, SubClass::RelativeVolume),
+
+        // Unique File Identifier
+        "UFID" => UniqueFileId::parse(data).map_or(SubClass::Unknown, SubClass::UniqueFileId),
+
+        // General Encapsulated Object
+        "GEOB" => GeneralObject::parse(data).map_or(SubClass::Unknown, SubClass::GeneralObject),
+
+        // URL
+        "WXXX" => UserLink::parse(data).map_or(SubClass::Unknown, SubClass::UserLink),
+        _url if first_char == 'W' => SubClass::Link(decode_text(split_text(data, &StringType::Latin1).0, &StringType::Latin1)),
+
+        // Lyrics
+        "USLT" => Lyrics::parse(data).map_or(SubClass::Unknown, SubClass::Lyrics),
+        "SYLT" => SyncedLyrics::parse(data).map_or(SubClass::Unknown, SubClass::SyncedLyrics),
+
+        // Event timing
+        "ETCO" => SubClass::Unknown,
+
+        // Popularimeter
+        "POPM" => Popularimeter::parse(data).map_or(SubClass::Unknown, SubClass::Popularimeter),
+
+        // Play counter
+        "PCNT" => SubClass::PlayCount(read_counter(data)),
+
+        // Private
+        "PRIV" => Private::parse(data).map_or(SubClass::Unknown, SubClass::Private),
+
+        // Ownership
+        "OWNE" => Ownership::parse(data).map_or(SubClass::Unknown, SubClass::Ownership),
+
+        // Chapter
+        "CHAP" => Chapter::parse(data, header).map_or(SubClass::Unknown, SubClass::Chapter),
+
+        // Table of Contents
+        "CTOC" => TableOfContents::parse(data, header).map_or(SubClass::Unknown, SubClass::TableOfContents),
 
-        let end = min(buf.len(), offset+len);
-        Ok(buf[offset..end].to_vec())
+        // Podcast
+        "PCST" if data.len() == 4 => SubClass::Podcast(BigEndian::read_u32(data)),
+
+        // Unknown
+        _ => SubClass::Unknown
+    }
+}
+
+// Compressed frames are zlib streams, reading stops just past the decompressed size given in the frame
+// so that a bad stream can't take up more memory than the frame claims to need
+fn inflate(data: &[u8], size: u32) -> Option<Vec<u8>> {
+    let mut buf = Vec::new();
+    ZlibDecoder::new(data).take(size as u64 + 1).read_to_end(&mut buf).ok()?;
+    Some(buf)
+}
+
+fn deflate(data: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data).ok()?;
+    encoder.finish().ok()
+}
+
+// Produces the frame body for the (already downgraded) frame id
+fn render_payload(frame_id: &str, sub: &SubClass, version: u8) -> Option<Vec<u8>> {
+    let data = match sub {
+        SubClass::Comment(comment) => comment.render(version),
+        SubClass::Picture(picture) => picture.render(version),
+        SubClass::Lyrics(lyrics) => lyrics.render(version),
+        SubClass::UserText(text) => text.render(version),
+        SubClass::UserLink(link) => link.render(version),
+        SubClass::Link(url) => encode_text(url, &StringType::Latin1, false),
+        SubClass::Popularimeter(popm) => popm.render(),
+        SubClass::PlayCount(count) => render_counter(*count),
+        SubClass::UniqueFileId(ufid) => ufid.render(),
+        SubClass::Chapter(chapter) => chapter.render(version),
+        SubClass::RelativeVolume(volume) if version == 3 => volume.render_rvad(),
+        SubClass::RelativeVolume(volume) => volume.render(),
+        SubClass::GeneralObject(object) => object.render(version),
+        SubClass::Private(private) => private.render(),
+        SubClass::Ownership(ownership) => ownership.render(version),
+        SubClass::Podcast(value) => value.to_be_bytes().to_vec(),
+        SubClass::TableOfContents(toc) => toc.render(version),
+        SubClass::SyncedLyrics(lyrics) => lyrics.render(version),
+
+        SubClass::Text(values, encoding) if frame_id.starts_with('T') => {
+            // ID3v2.3 only has a year field, not a timestamp
+            let values = match values.first() {
+                Some(year) if frame_id == "TYER" => vec![year.chars().take(4).collect()],
+                Some(_) if frame_id == "TCON" && version < 4 => vec![render_genre_references(values)],
+                _ => values.clone()
+            };
+            let encoding = text_encoding_for(&values.concat(), encoding, version);
+            let mut data = vec![encoding.clone() as u8];
+            data.extend(render_values(&values, &encoding, frame_id, version));
+            data
+        },
+
+        SubClass::Uint(val) if frame_id.starts_with('T') => {
+            let mut data = vec![StringType::Latin1 as u8];
+            data.extend(encode_text(&val.to_string(), &StringType::Latin1, false));
+            data
+        },
+
+        _ => return None
+    };
+
+    Some(data)
+}
+
+// Pick an encoding which is valid for the tag version and can represent the text
+fn text_encoding_for(text: &str, encoding: &StringType, version: u8) -> StringType {
+    match encoding {
+        StringType::Latin1 if text.chars().all(|ch| (ch as u32) < 0x100) => StringType::Latin1,
+        StringType::UTF8 | StringType::Latin1 if version > 3 => StringType::UTF8,
+        StringType::UTF16be if version > 3 => StringType::UTF16be,
+        _ => StringType::UTF16
+    }
+}
+
+pub(crate) fn encode_text(text: &str, encoding: &StringType, terminate: bool) -> Vec<u8> {
+    let mut buf = Vec::new();
+
+    match encoding {
+        StringType::Latin1 => {
+            buf.extend(text.chars().map(|ch| if (ch as u32) < 0x100 { ch as u8 } else { b'?' }));
+            if terminate {
+                buf.push(0);
+            }
+        },
+        StringType::UTF8 => {
+            buf.extend_from_slice(text.as_bytes());
+            if terminate {
+                buf.push(0);
+            }
+        },
+        StringType::UTF16be => {
+            for unit in text.encode_utf16() {
+                buf.extend_from_slice(&[(unit >> 8) as u8, unit as u8]);
+            }
+            if terminate {
+                buf.extend_from_slice(&[0, 0]);
+            }
+        },
+
+        // Write little-endian with a byte order mark
+        _ => {
+            buf.extend_from_slice(&[0xff, 0xfe]);
+            for unit in text.encode_utf16() {
+                buf.extend_from_slice(&[unit as u8, (unit >> 8) as u8]);
+            }
+            if terminate {
+                buf.extend_from_slice(&[0, 0]);
+            }
+        }
     }
+
+    buf
 }
 
 #[allow(dead_code)]
@@ -290,7 +595,7 @@ fn valid_frame_id(buf: &[u8]) -> bool {
     }
 
     for byte in buf {
-        if (*byte < 'A' as u8 || *byte > 'Z' as u8) && (*byte < '0' as u8 || *byte > '9' as u8) {
+        if (*byte < b'A' || *byte > b'Z') && (*byte < b'0' || *byte > b'9') {
             return false;
         }
     }
@@ -322,86 +627,1820 @@ impl From<u8> for StringType {
 }
 
 #[derive(Clone, Debug)]
-pub(crate) enum SubClass {
-    Text(String, StringType),
+pub enum SubClass {
+    Text(Vec<String>, StringType),
     Uint(u64),
+    Comment(Comment),
+    Picture(Picture),
+    Lyrics(Lyrics),
+    UserText(UserText),
+
+    // The url from W*** frames
+    Link(String),
+    UserLink(UserLink),
+    Popularimeter(Popularimeter),
+    PlayCount(u64),
+    UniqueFileId(UniqueFileId),
+    Chapter(Chapter),
+    RelativeVolume(RelativeVolume),
+    GeneralObject(GeneralObject),
+    Private(Private),
+    Ownership(Ownership),
+
+    // iTunes marks podcasts with a PCST frame, the value is always 0
+    Podcast(u32),
+    TableOfContents(TableOfContents),
+    SyncedLyrics(SyncedLyrics),
     Unknown
 }
 
-struct Header {
-    pub frame_id: String,
-    pub size: u64,
-    pub version: u8,
-    pub data_length_indicator: bool,
-    pub unsynch: bool,
-    pub tag_alter_preservation: bool,
-    pub file_alter_preservation: bool,
-    pub read_only: bool,
-    pub compression: bool,
-    pub encryption: bool,
-    pub grouping_ident: bool
+// Frame contents with their own type, which are always stored under the same frame id
+pub(crate) trait FrameContent: Sized {
+    const FRAME_ID: &'static str;
+
+    fn from_sub(sub: &SubClass) -> Option<&Self>;
+    fn into_sub(self) -> SubClass;
 }
 
-impl Header {
-    pub fn default(version: u8) -> Self {
+macro_rules! frame_content {
+    ($content:ident, $frame_id:expr) => {
+        impl FrameContent for $content {
+            const FRAME_ID: &'static str = $frame_id;
+
+            fn from_sub(sub: &SubClass) -> Option<&Self> {
+                match sub {
+                    SubClass::$content(content) => Some(content),
+                    _ => None
+                }
+            }
+
+            fn into_sub(self) -> SubClass {
+                SubClass::$content(self)
+            }
+        }
+    };
+}
+
+frame_content!(Comment, "COMM");
+frame_content!(Picture, "APIC");
+frame_content!(Lyrics, "USLT");
+frame_content!(UserText, "TXXX");
+frame_content!(UserLink, "WXXX");
+frame_content!(Popularimeter, "POPM");
+frame_content!(UniqueFileId, "UFID");
+frame_content!(Chapter, "CHAP");
+frame_content!(RelativeVolume, "RVA2");
+frame_content!(GeneralObject, "GEOB");
+frame_content!(Private, "PRIV");
+frame_content!(TableOfContents, "CTOC");
+frame_content!(SyncedLyrics, "SYLT");
+
+// COMM frames, there can be several as long as the language and description differ
+#[derive(Clone, Debug)]
+pub struct Comment {
+    pub encoding: StringType,
+
+    // ISO-639-2 language code, "XXX" if unknown
+    pub language: String,
+
+    // Empty for the main comment, programs use this to store data (eg. "iTunNORM")
+    pub description: String,
+    pub text: String
+}
+
+impl Comment {
+    pub fn new(language: &str, description: &str, text: &str) -> Self {
         Self{
-            frame_id: "".to_string(),
-            size: 0,
-            version: version,
-            data_length_indicator: false,
-            unsynch: false,
-            tag_alter_preservation: false,
-            file_alter_preservation: false,
-            read_only: false,
-            compression: false,
-            encryption: false,
-            grouping_ident: false,
+            encoding: StringType::Latin1,
+            language: language.to_string(),
+            description: description.to_string(),
+            text: text.to_string()
         }
     }
 
-    pub fn update(&mut self, version: u8) -> bool {
-        match self.frame_id.as_str() {
-            "TORY" => {
-                self.frame_id = "TDOR".to_string();
-            },
-            "TYER" => {
-                self.frame_id = "TDRC".to_string();
+    fn parse(data: &[u8]) -> Option<Self> {
+        let (encoding, language, description, text) = parse_language_text(data)?;
+        Some(Self{ encoding, language, description, text })
+    }
+
+    fn render(&self, version: u8) -> Vec<u8> {
+        render_language_text(&self.encoding, &self.language, &self.description, &self.text, version)
+    }
+}
+
+// USLT frames, there can be several as long as the language and description differ
+#[derive(Clone, Debug)]
+pub struct Lyrics {
+    pub encoding: StringType,
+
+    // ISO-639-2 language code, "XXX" if unknown
+    pub language: String,
+    pub description: String,
+    pub text: String
+}
+
+impl Lyrics {
+    pub fn new(language: &str, description: &str, text: &str) -> Self {
+        Self{
+            encoding: StringType::Latin1,
+            language: language.to_string(),
+            description: description.to_string(),
+            text: text.to_string()
+        }
+    }
+
+    fn parse(data: &[u8]) -> Option<Self> {
+        let (encoding, language, description, text) = parse_language_text(data)?;
+        Some(Self{ encoding, language, description, text })
+    }
+
+    fn render(&self, version: u8) -> Vec<u8> {
+        render_language_text(&self.encoding, &self.language, &self.description, &self.text, version)
+    }
+}
+
+// SYLT frames, text paired with the time it starts at
+#[derive(Clone, Debug)]
+pub struct SyncedLyrics {
+    pub encoding: StringType,
+
+    // ISO-639-2 language code, "XXX" if unknown
+    pub language: String,
+    pub timestamp_format: TimestampFormat,
+
+    // 1 for lyrics, see the ID3v2 spec for the other types
+    pub content_type: u8,
+    pub description: String,
+
+    // Ordered by time, new lines are usually marked by starting the text with '\n'
+    pub lines: Vec<(u32, String)>
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimestampFormat {
+    MpegFrames = 1,
+    Milliseconds = 2
+}
+
+impl SyncedLyrics {
+    pub fn new(language: &str, description: &str, lines: Vec<(u32, String)>) -> Self {
+        Self{
+            encoding: StringType::Latin1,
+            language: language.to_string(),
+            timestamp_format: TimestampFormat::Milliseconds,
+            content_type: 1,
+            description: description.to_string(),
+            lines
+        }
+    }
+
+    // Lines are read from "[mm:ss.xx]text", a line can have several timestamps
+    // Other tags (eg. "[ar:Artist]") are ignored
+    pub fn from_lrc(language: &str, description: &str, lrc: &str) -> Self {
+        let mut lines = Vec::new();
+
+        for line in lrc.lines() {
+            let mut rest = line.trim();
+            let mut times = Vec::new();
+
+            while let Some(end) = rest.strip_prefix('[').and_then(|tag| tag.find(']')) {
+                match parse_lrc_time(&rest[1..(end + 1)]) {
+                    Some(time) => times.push(time),
+                    None => break
+                }
+                rest = &rest[(end + 2)..];
             }
-            "IPLS" => {
-                self.frame_id = "TIPL".to_string();
-            },
-            _ => ()
+
+            for time in times {
+                lines.push((time, rest.to_string()));
+            }
+        }
+
+        lines.sort_by_key(|(time, _)| *time);
+        Self::new(language, description, lines)
+    }
+
+    // Only lyrics timed in milliseconds can be converted, the length of an MPEG frame isn't known here
+    pub fn to_lrc(&self) -> Option<String> {
+        if self.timestamp_format != TimestampFormat::Milliseconds {
+            return None;
+        }
+
+        let mut lrc = String::new();
+        for (time, text) in &self.lines {
+            let (minutes, seconds, hundredths) = (time / 60000, (time / 1000) % 60, (time % 1000) / 10);
+            lrc.push_str(&format!("[{:02}:{:02}.{:02}]{}\n", minutes, seconds, hundredths, text.trim_start_matches(['\r', '\n'])));
+        }
+
+        Some(lrc)
+    }
+
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 6 {
+            return None;
+        }
+
+        let encoding = StringType::from(data[0]);
+        let timestamp_format = match data[4] {
+            1 => TimestampFormat::MpegFrames,
+            2 => TimestampFormat::Milliseconds,
+            _ => return None
         };
+        let (description, mut rest) = split_text(&data[6..], &encoding);
+        let mut order = following_order(description, &encoding);
+
+        let mut lines = Vec::new();
+        while !rest.is_empty() {
+            let (text, after) = split_text(rest, &encoding);
+            if after.len() < 4 {
+                return None;
+            }
 
-        match version {
-            2 => match self.frame_id.as_str() {
-                "CRM" => false,
-                "EQU" => false,
-                "LNK" => false,
-                "RVA" => false,
-                "TIM" => false,
-                "TDA" => false,
-                "TSI" => false,
-                _ => true,
-            },
-            3 => match self.frame_id.as_str() {
-                "EQUA" => false,
-                "RVAD" => false,
-                "TIME" => false,
-                "TRDA" => false,
-                "TSIZ" => false,
-                "TDAT" => false,
-                _ => true
-            },
-            _ => true
+            order = following_order(text, &order);
+            lines.push((BigEndian::read_u32(&after[0..4]), decode_text(text, &order)));
+            rest = &after[4..];
+        }
+
+        Some(Self{
+            language: decode_text(&data[1..4], &StringType::Latin1),
+            timestamp_format,
+            content_type: data[5],
+            description: decode_text(description, &encoding),
+            lines,
+            encoding
+        })
+    }
+
+    fn render(&self, version: u8) -> Vec<u8> {
+        let all_text = self.lines.iter().fold(self.description.to_string(), |all, (_, text)| all + text);
+        let encoding = text_encoding_for(&all_text, &self.encoding, version);
+
+        let mut data = vec![encoding.clone() as u8];
+        data.extend(render_language(&self.language));
+        data.push(self.timestamp_format as u8);
+        data.push(self.content_type);
+        data.extend(encode_text(&self.description, &encoding, true));
+
+        for (time, text) in &self.lines {
+            data.extend(encode_text(text, &encoding, true));
+            data.extend_from_slice(&time.to_be_bytes());
         }
+
+        data
     }
 }
 
-pub fn sizeof_frame_header(version: u8) -> u64 {
-    if version < 3 {
-        6
-    } else {
-        10
+// "mm:ss", "mm:ss.xx" or "mm:ss.xxx", in milliseconds
+fn parse_lrc_time(time: &str) -> Option<u32> {
+    let (minutes, seconds) = time.split_once(':')?;
+    let (seconds, fraction) = seconds.split_once('.').unwrap_or((seconds, "0"));
+
+    let fraction = match fraction.len() {
+        1 => fraction.parse::<u32>().ok()? * 100,
+        2 => fraction.parse::<u32>().ok()? * 10,
+        3 => fraction.parse::<u32>().ok()?,
+        _ => return None
+    };
+
+    // Timestamps past u32::MAX milliseconds (about 49 days) can't be stored
+    minutes.parse::<u32>().ok()?.checked_mul(60000)?
+        .checked_add(seconds.parse::<u32>().ok()?.checked_mul(1000)?)?
+        .checked_add(fraction)
+}
+
+// GEOB frames, a file embedded in the tag
+#[derive(Clone, Debug)]
+pub struct GeneralObject {
+    pub encoding: StringType,
+    pub mime_type: String,
+    pub filename: String,
+
+    // Only one object can have a given description
+    pub description: String,
+    pub data: Vec<u8>
+}
+
+impl GeneralObject {
+    pub fn new(mime_type: &str, filename: &str, description: &str, data: Vec<u8>) -> Self {
+        Self{
+            encoding: StringType::Latin1,
+            mime_type: mime_type.to_string(),
+            filename: filename.to_string(),
+            description: description.to_string(),
+            data
+        }
+    }
+
+    fn parse(data: &[u8]) -> Option<Self> {
+        let (encoding, data) = data.split_first()?;
+        let encoding = StringType::from(*encoding);
+        let (mime_type, rest) = split_text(data, &StringType::Latin1);
+        let (filename, rest) = split_text(rest, &encoding);
+        let (description, data) = split_text(rest, &encoding);
+        let order = following_order(filename, &encoding);
+
+        Some(Self{
+            mime_type: decode_text(mime_type, &StringType::Latin1),
+            filename: decode_text(filename, &encoding),
+            description: decode_text(description, &order),
+            data: data.to_vec(),
+            encoding
+        })
+    }
+
+    fn render(&self, version: u8) -> Vec<u8> {
+        let encoding = text_encoding_for(&format!("{}{}", self.filename, self.description), &self.encoding, version);
+
+        let mut data = vec![encoding.clone() as u8];
+        data.extend(encode_text(&self.mime_type, &StringType::Latin1, true));
+        data.extend(encode_text(&self.filename, &encoding, true));
+        data.extend(encode_text(&self.description, &encoding, true));
+        data.extend_from_slice(&self.data);
+        data
+    }
+}
+
+// PRIV frames, data only the owner (usually a url or email) knows how to read
+#[derive(Clone, Debug)]
+pub struct Private {
+    pub owner: String,
+    pub data: Vec<u8>
+}
+
+impl Private {
+    pub fn new(owner: &str, data: Vec<u8>) -> Self {
+        Self{ owner: owner.to_string(), data }
+    }
+
+    fn parse(data: &[u8]) -> Option<Self> {
+        let (owner, data) = split_text(data, &StringType::Latin1);
+        if owner.is_empty() {
+            return None;
+        }
+
+        Some(Self{
+            owner: decode_text(owner, &StringType::Latin1),
+            data: data.to_vec()
+        })
+    }
+
+    fn render(&self) -> Vec<u8> {
+        let mut data = encode_text(&self.owner, &StringType::Latin1, true);
+        data.extend_from_slice(&self.data);
+        data
+    }
+}
+
+// OWNE frames, how the file was bought
+#[derive(Clone, Debug)]
+pub struct Ownership {
+    pub encoding: StringType,
+
+    // Currency code followed by the amount (eg. "USD10.99")
+    pub price_paid: String,
+
+    // Formatted as "YYYYMMDD"
+    pub purchase_date: String,
+    pub seller: String
+}
+
+impl Ownership {
+    pub fn new(price_paid: &str, purchase_date: &str, seller: &str) -> Self {
+        Self{
+            encoding: StringType::Latin1,
+            price_paid: price_paid.to_string(),
+            purchase_date: purchase_date.to_string(),
+            seller: seller.to_string()
+        }
+    }
+
+    fn parse(data: &[u8]) -> Option<Self> {
+        let (encoding, data) = data.split_first()?;
+        let encoding = StringType::from(*encoding);
+        let (price_paid, rest) = split_text(data, &StringType::Latin1);
+        if rest.len() < 8 {
+            return None;
+        }
+
+        Some(Self{
+            price_paid: decode_text(price_paid, &StringType::Latin1),
+            purchase_date: decode_text(&rest[0..8], &StringType::Latin1),
+            seller: decode_text(&rest[8..], &encoding),
+            encoding
+        })
+    }
+
+    fn render(&self, version: u8) -> Vec<u8> {
+        let encoding = text_encoding_for(&self.seller, &self.encoding, version);
+
+        let mut date = encode_text(&self.purchase_date, &StringType::Latin1, false);
+        date.resize(8, b'0');
+
+        let mut data = vec![encoding.clone() as u8];
+        data.extend(encode_text(&self.price_paid, &StringType::Latin1, true));
+        data.extend(date);
+        data.extend(encode_text(&self.seller, &encoding, false));
+        data
+    }
+}
+
+// RVA2 frames (and v2.3 RVAD frames), the volume adjustment for each channel
+#[derive(Clone, Debug)]
+pub struct RelativeVolume {
+    // What the adjustment is for (eg. "track" or "album"), always empty for RVAD frames
+    pub identification: String,
+    pub channels: Vec<ChannelAdjustment>
+}
+
+#[derive(Clone, Debug)]
+pub struct ChannelAdjustment {
+    pub channel: ChannelType,
+
+    // In decibels
+    pub gain: f64,
+
+    // As a fraction of the largest possible sample, 0 if not known
+    pub peak: f64
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelType {
+    Other = 0,
+    Master = 1,
+    FrontRight = 2,
+    FrontLeft = 3,
+    BackRight = 4,
+    BackLeft = 5,
+    FrontCentre = 6,
+    BackCentre = 7,
+    Subwoofer = 8
+}
+
+impl From<u8> for ChannelType {
+    fn from(val: u8) -> Self {
+        match val {
+            1 => ChannelType::Master,
+            2 => ChannelType::FrontRight,
+            3 => ChannelType::FrontLeft,
+            4 => ChannelType::BackRight,
+            5 => ChannelType::BackLeft,
+            6 => ChannelType::FrontCentre,
+            7 => ChannelType::BackCentre,
+            8 => ChannelType::Subwoofer,
+            _ => ChannelType::Other
+        }
+    }
+}
+
+// The channels in an RVAD frame, in the order they're stored
+static RVAD_CHANNELS: [ChannelType; 6] = [
+    ChannelType::FrontRight, ChannelType::FrontLeft,
+    ChannelType::BackRight, ChannelType::BackLeft,
+    ChannelType::FrontCentre, ChannelType::Subwoofer
+];
+
+impl RelativeVolume {
+    pub fn new(identification: &str, channels: Vec<ChannelAdjustment>) -> Self {
+        Self{ identification: identification.to_string(), channels }
+    }
+
+    pub fn channel(&self, channel: ChannelType) -> Option<&ChannelAdjustment> {
+        self.channels.iter().find(|adjustment| adjustment.channel == channel)
+    }
+
+    // Gains are stored in 1/512 dB, peaks are stored with their precision in bits
+    fn parse(data: &[u8]) -> Option<Self> {
+        let (identification, mut rest) = split_text(data, &StringType::Latin1);
+
+        let mut channels = Vec::new();
+        while rest.len() >= 4 {
+            let bits = rest[3] as usize;
+            let end = 4 + bits.div_ceil(8);
+            if rest.len() < end {
+                return None;
+            }
+
+            channels.push(ChannelAdjustment{
+                channel: ChannelType::from(rest[0]),
+                gain: BigEndian::read_i16(&rest[1..3]) as f64 / 512.0,
+                peak: read_fraction(&rest[4..end], bits)
+            });
+            rest = &rest[end..];
+        }
+
+        Some(Self{
+            identification: decode_text(identification, &StringType::Latin1),
+            channels
+        })
+    }
+
+    fn render(&self) -> Vec<u8> {
+        let mut data = encode_text(&self.identification, &StringType::Latin1, true);
+
+        for adjustment in &self.channels {
+            let gain = (adjustment.gain * 512.0).round().clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+
+            data.push(adjustment.channel as u8);
+            data.extend_from_slice(&gain.to_be_bytes());
+            if adjustment.peak > 0.0 {
+                data.push(16);
+                data.extend_from_slice(&write_fraction(adjustment.peak).to_be_bytes());
+            } else {
+                data.push(0);
+            }
+        }
+
+        data
+    }
+
+    // RVAD stores each change as a fraction of the sample's value, with the signs kept in a separate byte
+    // The changes are followed by the peaks, for right and left, then right back and left back, then centre and then bass
+    fn parse_rvad(data: &[u8]) -> Option<Self> {
+        if data.len() < 2 || data[1] == 0 {
+            return None;
+        }
+
+        let increment = data[0];
+        let bits = data[1] as usize;
+        let width = bits.div_ceil(8);
+        let fields = data[2..].chunks_exact(width).collect::<Vec<_>>();
+
+        // Right and left are mandatory, the other groups are optional
+        let mut channels = Vec::new();
+        let mut field = 0;
+        for (i, group) in [&[0, 1][..], &[2, 3], &[4], &[5]].iter().enumerate() {
+            if field + group.len() * 2 > fields.len() {
+                if i == 0 {
+                    return None;
+                }
+                break;
+            }
+
+            for (j, channel) in group.iter().enumerate() {
+                let change = read_fraction(fields[field + j], bits);
+                let factor = if increment & (1 << channel) != 0 { 1.0 + change } else { 1.0 - change };
+
+                channels.push(ChannelAdjustment{
+                    channel: RVAD_CHANNELS[*channel],
+                    gain: 20.0 * factor.max(f64::MIN_POSITIVE).log10(),
+                    peak: read_fraction(fields[field + group.len() + j], bits)
+                });
+            }
+            field += group.len() * 2;
+        }
+
+        Some(Self{
+            identification: String::new(),
+            channels
+        })
+    }
+
+    // Channels RVAD can't represent are dropped, master volume is applied to the front channels
+    fn render_rvad(&self) -> Vec<u8> {
+        let master = self.channel(ChannelType::Master);
+        let adjustments = RVAD_CHANNELS.iter().map(|channel| match (self.channel(*channel), channel) {
+            (None, ChannelType::FrontRight) | (None, ChannelType::FrontLeft) => master,
+            (adjustment, _) => adjustment
+        }).collect::<Vec<_>>();
+
+        // Groups are positional, so every group before the last one used has to be written
+        let count = match adjustments.iter().rposition(|adjustment| adjustment.is_some()) {
+            Some(5) => 6,
+            Some(4) => 5,
+            Some(2) | Some(3) => 4,
+            _ => 2
+        };
+
+        let mut increment = 0;
+        let mut changes = Vec::new();
+        let mut peaks = Vec::new();
+        for (i, adjustment) in adjustments.iter().take(count).enumerate() {
+            let (gain, peak) = adjustment.map_or((0.0, 0.0), |adjustment| (adjustment.gain, adjustment.peak));
+            let factor = 10f64.powf(gain / 20.0);
+            if factor >= 1.0 {
+                increment |= 1 << i;
+            }
+
+            changes.push(write_fraction((factor - 1.0).abs()));
+            peaks.push(write_fraction(peak));
+        }
+
+        let mut data = vec![increment, 16];
+        for group in [0..2, 2..4, 4..5, 5..6] {
+            if group.start >= count {
+                break;
+            }
+
+            for i in group.clone() {
+                data.extend_from_slice(&changes[i].to_be_bytes());
+            }
+            for i in group {
+                data.extend_from_slice(&peaks[i].to_be_bytes());
+            }
+        }
+
+        data
+    }
+}
+
+// Reads an unsigned integer of `bits` precision as a fraction of its largest value
+fn read_fraction(buf: &[u8], bits: usize) -> f64 {
+    if bits == 0 {
+        return 0.0;
+    }
+
+    let value = buf.iter().fold(0.0, |value, byte| value * 256.0 + *byte as f64);
+    value / (2f64.powi(bits as i32) - 1.0)
+}
+
+// Fractions are always written with 16 bits, so RVAD can't increase the volume by more than ~6dB
+fn write_fraction(fraction: f64) -> u16 {
+    (fraction.clamp(0.0, 1.0) * u16::MAX as f64).round() as u16
+}
+
+// CHAP frames, a section of the audio with its own frames (usually TIT2, APIC or WXXX)
+#[derive(Clone, Debug)]
+pub struct Chapter {
+    pub element_id: String,
+
+    // In milliseconds
+    pub start_time: u32,
+    pub end_time: u32,
+
+    // Offsets of the first byte of the chapter's audio from the start of the file, if known
+    pub start_offset: Option<u32>,
+    pub end_offset: Option<u32>,
+    pub frames: Vec<Frame>
+}
+
+impl Chapter {
+    pub fn new(element_id: &str, start_time: u32, end_time: u32) -> Self {
+        Self{
+            element_id: element_id.to_string(),
+            start_time,
+            end_time,
+            start_offset: None,
+            end_offset: None,
+            frames: Vec::new()
+        }
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        embedded_title(&self.frames)
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        set_embedded_title(&mut self.frames, title);
+    }
+
+    fn parse(data: &[u8], header: &tag::TagHeader) -> Option<Self> {
+        let (element_id, rest) = split_text(data, &StringType::Latin1);
+        if rest.len() < 16 {
+            return None;
+        }
+
+        // 0xFFFFFFFF marks an offset as unused
+        let offset = |buf: &[u8]| Some(BigEndian::read_u32(buf)).filter(|offset| *offset != u32::MAX);
+
+        Some(Self{
+            element_id: decode_text(element_id, &StringType::Latin1),
+            start_time: BigEndian::read_u32(&rest[0..4]),
+            end_time: BigEndian::read_u32(&rest[4..8]),
+            start_offset: offset(&rest[8..12]),
+            end_offset: offset(&rest[12..16]),
+            frames: parse_embedded_frames(&rest[16..], header)?
+        })
+    }
+
+    fn render(&self, version: u8) -> Vec<u8> {
+        let mut data = encode_text(&self.element_id, &StringType::Latin1, true);
+        data.extend_from_slice(&self.start_time.to_be_bytes());
+        data.extend_from_slice(&self.end_time.to_be_bytes());
+        data.extend_from_slice(&self.start_offset.unwrap_or(u32::MAX).to_be_bytes());
+        data.extend_from_slice(&self.end_offset.unwrap_or(u32::MAX).to_be_bytes());
+        data.extend(render_embedded_frames(&self.frames, version));
+        data
+    }
+}
+
+// CTOC frames, a list of chapters (or other tables of contents) by element id
+#[derive(Clone, Debug)]
+pub struct TableOfContents {
+    pub element_id: String,
+
+    // Only one table of contents should be top level, the rest are reached through its children
+    pub top_level: bool,
+
+    // Whether the children have to be played in order
+    pub ordered: bool,
+    pub children: Vec<String>,
+    pub frames: Vec<Frame>
+}
+
+impl TableOfContents {
+    pub fn new(element_id: &str, children: Vec<String>) -> Self {
+        Self{
+            element_id: element_id.to_string(),
+            top_level: false,
+            ordered: true,
+            children,
+            frames: Vec::new()
+        }
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        embedded_title(&self.frames)
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        set_embedded_title(&mut self.frames, title);
+    }
+
+    fn parse(data: &[u8], header: &tag::TagHeader) -> Option<Self> {
+        let (element_id, rest) = split_text(data, &StringType::Latin1);
+        if rest.len() < 2 {
+            return None;
+        }
+
+        let mut children = Vec::new();
+        let mut rest_children = &rest[2..];
+        for _ in 0..rest[1] {
+            let (child, after) = split_text(rest_children, &StringType::Latin1);
+            children.push(decode_text(child, &StringType::Latin1));
+            rest_children = after;
+        }
+
+        Some(Self{
+            element_id: decode_text(element_id, &StringType::Latin1),
+            top_level: rest[0] & 0b10 != 0,
+            ordered: rest[0] & 0b1 != 0,
+            children,
+            frames: parse_embedded_frames(rest_children, header)?
+        })
+    }
+
+    fn render(&self, version: u8) -> Vec<u8> {
+        let mut data = encode_text(&self.element_id, &StringType::Latin1, true);
+        data.push((self.top_level as u8) << 1 | self.ordered as u8);
+
+        // The entry count is a single byte
+        data.push(min(self.children.len(), 255) as u8);
+        for child in self.children.iter().take(255) {
+            data.extend(encode_text(child, &StringType::Latin1, true));
+        }
+
+        data.extend(render_embedded_frames(&self.frames, version));
+        data
+    }
+}
+
+// The sub-frames have already had the tag's unsynchronisation removed
+fn parse_embedded_frames(data: &[u8], header: &tag::TagHeader) -> Option<Vec<Frame>> {
+    let header = tag::TagHeader{ unsynch: false, ..header.clone() };
+    let header_size = sizeof_frame_header(header.major_version) as usize;
+
+    let mut buf = data.to_vec();
+    let mut frames = Vec::new();
+    let mut pos = 0;
+    while pos + header_size < buf.len() && buf[pos] != 0 {
+        let frame = match Frame::from_buffer(&mut buf[pos..], &header).ok()? {
+            Some(frame) => frame,
+            None => break
+        };
+
+        pos += frame.size + header_size;
+        frames.push(frame);
+    }
+
+    Some(frames)
+}
+
+fn render_embedded_frames(frames: &[Frame], version: u8) -> Vec<u8> {
+    frames.iter().filter_map(|frame| frame.render(version, frame.flags.compression)).flatten().collect()
+}
+
+fn embedded_title(frames: &[Frame]) -> Option<&str> {
+    frames.iter().find(|frame| frame.frame_id == "TIT2").and_then(|frame| match frame.sub {
+        SubClass::Text(ref title, _) => title.first().map(String::as_str),
+        _ => None
+    })
+}
+
+fn set_embedded_title(frames: &mut Vec<Frame>, title: &str) {
+    frames.retain(|frame| frame.frame_id != "TIT2");
+    frames.push(Frame::new("TIT2", SubClass::Text(vec![title.to_string()], StringType::Latin1)));
+}
+
+// UFID frames, an identifier (up to 64 bytes) in the database run by the owner
+#[derive(Clone, Debug)]
+pub struct UniqueFileId {
+    // Usually a url for the database (eg. "http://musicbrainz.org")
+    pub owner: String,
+    pub identifier: Vec<u8>
+}
+
+impl UniqueFileId {
+    pub fn new(owner: &str, identifier: Vec<u8>) -> Self {
+        Self{ owner: owner.to_string(), identifier }
+    }
+
+    fn parse(data: &[u8]) -> Option<Self> {
+        let (owner, identifier) = split_text(data, &StringType::Latin1);
+        if owner.is_empty() {
+            return None;
+        }
+
+        Some(Self{
+            owner: decode_text(owner, &StringType::Latin1),
+            identifier: identifier.to_vec()
+        })
+    }
+
+    fn render(&self) -> Vec<u8> {
+        let mut data = encode_text(&self.owner, &StringType::Latin1, true);
+        data.extend_from_slice(&self.identifier);
+        data
+    }
+}
+
+// POPM frames, a rating and play count for the user with the given email
+#[derive(Clone, Debug)]
+pub struct Popularimeter {
+    pub email: String,
+
+    // 1 is the worst and 255 the best, 0 is unrated
+    pub rating: u8,
+    pub counter: u64
+}
+
+// Ratings used by Windows Media Player for 1 to 5 stars
+static STAR_RATINGS: [u8; 6] = [0, 1, 64, 128, 196, 255];
+
+impl Popularimeter {
+    pub fn new(email: &str, rating: u8, counter: u64) -> Self {
+        Self{ email: email.to_string(), rating, counter }
+    }
+
+    // The rating as 0 to 5 stars, 0 being unrated
+    pub fn stars(&self) -> u8 {
+        match self.rating {
+            0 => 0,
+            1..=31 => 1,
+            32..=95 => 2,
+            96..=159 => 3,
+            160..=223 => 4,
+            _ => 5
+        }
+    }
+
+    // Stars above 5 are treated as 5
+    pub fn set_stars(&mut self, stars: u8) {
+        self.rating = STAR_RATINGS[min(stars as usize, 5)];
+    }
+
+    fn parse(data: &[u8]) -> Option<Self> {
+        let (email, rest) = split_text(data, &StringType::Latin1);
+        let (rating, counter) = rest.split_first()?;
+
+        Some(Self{
+            email: decode_text(email, &StringType::Latin1),
+            rating: *rating,
+            counter: read_counter(counter)
+        })
+    }
+
+    fn render(&self) -> Vec<u8> {
+        let mut data = encode_text(&self.email, &StringType::Latin1, true);
+        data.push(self.rating);
+        data.extend(render_counter(self.counter));
+        data
+    }
+}
+
+// Counters are big endian integers of at least 4 bytes, which can grow as needed
+// Counters too large for a u64 are capped
+fn read_counter(buf: &[u8]) -> u64 {
+    buf.iter().fold(0u64, |count, byte| count.checked_mul(256).map_or(u64::MAX, |count| count | *byte as u64))
+}
+
+fn render_counter(count: u64) -> Vec<u8> {
+    let bytes = count.to_be_bytes();
+    let start = min(bytes.iter().position(|byte| *byte != 0).unwrap_or(8), 4);
+    bytes[start..].to_vec()
+}
+
+// TXXX frames, the description identifies the value (eg. "MusicBrainz Album Id")
+#[derive(Clone, Debug)]
+pub struct UserText {
+    pub encoding: StringType,
+    pub description: String,
+    pub values: Vec<String>
+}
+
+impl UserText {
+    pub fn new(description: &str, values: Vec<String>) -> Self {
+        Self{
+            encoding: StringType::Latin1,
+            description: description.to_string(),
+            values
+        }
+    }
+
+    fn parse(data: &[u8]) -> Option<Self> {
+        let (encoding, data) = data.split_first()?;
+        let encoding = StringType::from(*encoding);
+        let (description, values) = split_text(data, &encoding);
+
+        Some(Self{
+            description: decode_text(description, &encoding),
+            values: split_values(values, &following_order(description, &encoding)),
+            encoding
+        })
+    }
+
+    fn render(&self, version: u8) -> Vec<u8> {
+        let encoding = text_encoding_for(&format!("{}{}", self.description, self.values.concat()), &self.encoding, version);
+
+        let mut data = vec![encoding.clone() as u8];
+        data.extend(encode_text(&self.description, &encoding, true));
+        data.extend(render_values(&self.values, &encoding, "TXXX", version));
+        data
+    }
+}
+
+// WXXX frames, urls are always Latin1
+#[derive(Clone, Debug)]
+pub struct UserLink {
+    pub encoding: StringType,
+    pub description: String,
+    pub url: String
+}
+
+impl UserLink {
+    pub fn new(description: &str, url: &str) -> Self {
+        Self{
+            encoding: StringType::Latin1,
+            description: description.to_string(),
+            url: url.to_string()
+        }
+    }
+
+    fn parse(data: &[u8]) -> Option<Self> {
+        let (encoding, data) = data.split_first()?;
+        let encoding = StringType::from(*encoding);
+        let (description, url) = split_text(data, &encoding);
+
+        Some(Self{
+            description: decode_text(description, &encoding),
+            url: decode_text(split_text(url, &StringType::Latin1).0, &StringType::Latin1),
+            encoding
+        })
+    }
+
+    fn render(&self, version: u8) -> Vec<u8> {
+        let encoding = text_encoding_for(&self.description, &self.encoding, version);
+
+        let mut data = vec![encoding.clone() as u8];
+        data.extend(encode_text(&self.description, &encoding, true));
+        data.extend(encode_text(&self.url, &StringType::Latin1, false));
+        data
+    }
+}
+
+// The W*** frames which hold a single url
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkType {
+    Commercial,
+    Copyright,
+    AudioFile,
+    Artist,
+    AudioSource,
+    RadioStation,
+    Payment,
+    Publisher
+}
+
+impl LinkType {
+    pub fn frame_id(&self) -> &'static str {
+        match self {
+            LinkType::Commercial => "WCOM",
+            LinkType::Copyright => "WCOP",
+            LinkType::AudioFile => "WOAF",
+            LinkType::Artist => "WOAR",
+            LinkType::AudioSource => "WOAS",
+            LinkType::RadioStation => "WORS",
+            LinkType::Payment => "WPAY",
+            LinkType::Publisher => "WPUB"
+        }
+    }
+}
+
+// The encoding for the UTF-16 strings following `buf` which don't have their own byte order mark
+fn following_order(buf: &[u8], encoding: &StringType) -> StringType {
+    match (encoding, buf) {
+        (StringType::UTF16, [0xfe, 0xff, ..]) | (StringType::UTF16le, [0xfe, 0xff, ..]) => StringType::UTF16be,
+        (StringType::UTF16, [0xff, 0xfe, ..]) | (StringType::UTF16be, [0xff, 0xfe, ..]) => StringType::UTF16le,
+        _ => encoding.clone()
+    }
+}
+
+// Values are separated by nulls, each value has its own byte order mark in UTF-16
+pub(crate) fn split_values(mut buf: &[u8], encoding: &StringType) -> Vec<String> {
+    let mut values = Vec::new();
+
+    // Values without a byte order mark use the one from the value before them
+    let mut order = encoding.clone();
+
+    loop {
+        let (value, rest) = split_text(buf, encoding);
+        order = following_order(value, &order);
+        values.push(decode_text(value, &order));
+
+        if rest.is_empty() {
+            return values;
+        }
+        buf = rest;
+    }
+}
+
+// ID3v2.3 only allows multiple values in a few frames, separated with '/'
+// Other frames keep their values null separated like in ID3v2.4, which most readers accept
+fn render_values(values: &[String], encoding: &StringType, frame_id: &str, version: u8) -> Vec<u8> {
+    if version < 4 && matches!(frame_id, "TCOM" | "TEXT" | "TOLY" | "TOPE" | "TPE1") {
+        return encode_text(&values.join("/"), encoding, false);
+    }
+
+    let mut data = Vec::new();
+    for (i, value) in values.iter().enumerate() {
+        data.extend(encode_text(value, encoding, i + 1 < values.len()));
+    }
+    data
+}
+
+// ID3v2.3 genres are references to ID3v1 genres (eg. "(17)(18)"), other genres follow as the refinement
+// A refinement starting with '(' is escaped as "(("
+fn render_genre_references(genres: &[String]) -> String {
+    let mut references = String::new();
+    let mut refinements = Vec::new();
+
+    for genre in genres {
+        let index = genre.parse::<usize>().ok()
+            .filter(|index| meta::genre_name(*index).is_some())
+            .map(|index| index as u8)
+            .or_else(|| meta::genre_index(genre));
+
+        match (genre.as_str(), index) {
+            ("Remix", _) | ("RX", _) => references.push_str("(RX)"),
+            ("Cover", _) | ("CR", _) => references.push_str("(CR)"),
+            (_, Some(index)) => references.push_str(&format!("({})", index)),
+            (genre, None) => refinements.push(genre)
+        }
+    }
+
+    let refinement = refinements.join("/");
+    if refinement.starts_with('(') {
+        references.push('(');
+    }
+    references + &refinement
+}
+
+// COMM and USLT frames are laid out as encoding, language, description and text
+fn parse_language_text(data: &[u8]) -> Option<(StringType, String, String, String)> {
+    if data.len() < 4 {
+        return None;
+    }
+
+    let encoding = StringType::from(data[0]);
+    let (description, text) = split_text(&data[4..], &encoding);
+
+    Some((
+        encoding.clone(),
+        decode_text(&data[1..4], &StringType::Latin1),
+        decode_text(description, &encoding),
+        decode_text(text, &following_order(description, &encoding))
+    ))
+}
+
+fn render_language_text(encoding: &StringType, language: &str, description: &str, text: &str, version: u8) -> Vec<u8> {
+    let encoding = text_encoding_for(&format!("{}{}", description, text), encoding, version);
+
+    let mut data = vec![encoding.clone() as u8];
+    data.extend(render_language(language));
+    data.extend(encode_text(description, &encoding, true));
+    data.extend(encode_text(text, &encoding, false));
+    data
+}
+
+// APIC frames, only one picture can have a given description
+#[derive(Clone, Debug)]
+pub struct Picture {
+    pub encoding: StringType,
+    pub mime_type: String,
+    pub picture_type: PictureType,
+    pub description: String,
+    pub data: Vec<u8>
+}
+
+impl Picture {
+    pub fn new(mime_type: &str, picture_type: PictureType, description: &str, data: Vec<u8>) -> Self {
+        Self{
+            encoding: StringType::Latin1,
+            mime_type: mime_type.to_string(),
+            picture_type,
+            description: description.to_string(),
+            data
+        }
+    }
+
+    // v2.2 has a 3 character image format ("PNG" or "JPG") instead of a MIME type
+    fn parse(data: &[u8], version: u8) -> Option<Self> {
+        if data.len() < 2 {
+            return None;
+        }
+
+        let encoding = StringType::from(data[0]);
+        let (mime_type, rest) = if version < 3 {
+            if data.len() < 4 {
+                return None;
+            }
+
+            let format = decode_text(&data[1..4], &StringType::Latin1).to_lowercase();
+            let mime_type = match format.as_str() {
+                "jpg" => "image/jpeg".to_string(),
+                format => format!("image/{}", format)
+            };
+            (mime_type, &data[4..])
+        } else {
+            let (mime_type, rest) = split_text(&data[1..], &StringType::Latin1);
+            (decode_text(mime_type, &StringType::Latin1), rest)
+        };
+
+        let (picture_type, rest) = rest.split_first()?;
+        let (description, data) = split_text(rest, &encoding);
+
+        Some(Self{
+            mime_type,
+            picture_type: PictureType::from(*picture_type),
+            description: decode_text(description, &encoding),
+            data: data.to_vec(),
+            encoding
+        })
+    }
+
+    fn render(&self, version: u8) -> Vec<u8> {
+        let encoding = text_encoding_for(&self.description, &self.encoding, version);
+
+        let mut data = vec![encoding.clone() as u8];
+        data.extend(encode_text(&self.mime_type, &StringType::Latin1, true));
+        data.push(self.picture_type as u8);
+        data.extend(encode_text(&self.description, &encoding, true));
+        data.extend_from_slice(&self.data);
+        data
+    }
+}
+
+// The picture types defined by the ID3v2 spec, unknown types are read as `Other`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PictureType {
+    Other = 0,
+    FileIcon = 1,
+    OtherFileIcon = 2,
+    FrontCover = 3,
+    BackCover = 4,
+    LeafletPage = 5,
+    Media = 6,
+    LeadArtist = 7,
+    Artist = 8,
+    Conductor = 9,
+    Band = 10,
+    Composer = 11,
+    Lyricist = 12,
+    RecordingLocation = 13,
+    DuringRecording = 14,
+    DuringPerformance = 15,
+    MovieScreenCapture = 16,
+    ColouredFish = 17,
+    Illustration = 18,
+    BandLogo = 19,
+    PublisherLogo = 20
+}
+
+impl From<u8> for PictureType {
+    fn from(val: u8) -> Self {
+        match val {
+            1 => PictureType::FileIcon,
+            2 => PictureType::OtherFileIcon,
+            3 => PictureType::FrontCover,
+            4 => PictureType::BackCover,
+            5 => PictureType::LeafletPage,
+            6 => PictureType::Media,
+            7 => PictureType::LeadArtist,
+            8 => PictureType::Artist,
+            9 => PictureType::Conductor,
+            10 => PictureType::Band,
+            11 => PictureType::Composer,
+            12 => PictureType::Lyricist,
+            13 => PictureType::RecordingLocation,
+            14 => PictureType::DuringRecording,
+            15 => PictureType::DuringPerformance,
+            16 => PictureType::MovieScreenCapture,
+            17 => PictureType::ColouredFish,
+            18 => PictureType::Illustration,
+            19 => PictureType::BandLogo,
+            20 => PictureType::PublisherLogo,
+            _ => PictureType::Other
+        }
+    }
+}
+
+// Languages are always 3 characters
+fn render_language(language: &str) -> Vec<u8> {
+    let mut buf = encode_text(language, &StringType::Latin1, false);
+    buf.resize(3, b'X');
+    buf
+}
+
+// Split a null terminated string from the start of the buffer, the terminator is dropped
+// If there isn't a terminator the whole buffer is taken
+pub(crate) fn split_text<'a>(buf: &'a [u8], encoding: &StringType) -> (&'a [u8], &'a [u8]) {
+    let width = match encoding {
+        StringType::UTF16 | StringType::UTF16be | StringType::UTF16le => 2,
+        _ => 1
+    };
+
+    let mut pos = 0;
+    while pos + width <= buf.len() {
+        if buf[pos..(pos + width)].iter().all(|byte| *byte == 0) {
+            return (&buf[..pos], &buf[(pos + width)..]);
+        }
+        pos += width;
+    }
+
+    (buf, &[])
+}
+
+// Decode a string, ignoring any trailing nulls
+// UTF-16 strings without a byte order mark are big endian, unless the encoding says otherwise
+pub(crate) fn decode_text(buf: &[u8], encoding: &StringType) -> String {
+    let text = match encoding {
+        StringType::UTF8 => String::from_utf8_lossy(buf).into_owned(),
+        StringType::UTF16 | StringType::UTF16be | StringType::UTF16le => {
+            let (big_endian, buf) = match (encoding, buf) {
+                (_, [0xfe, 0xff, rest @ ..]) => (true, rest),
+                (_, [0xff, 0xfe, rest @ ..]) => (false, rest),
+                _ => (!matches!(encoding, StringType::UTF16le), buf)
+            };
+
+            // An odd trailing byte can't be part of a character
+            let units = buf.chunks_exact(2)
+                .map(|pair| if big_endian { BigEndian::read_u16(pair) } else { LittleEndian::read_u16(pair) })
+                .collect::<Vec<_>>();
+            String::from_utf16_lossy(&units)
+        },
+
+        // Invalid encodings are treated as Latin1 so that nothing is lost
+        _ => buf.iter().map(|byte| *byte as char).collect()
+    };
+
+    text.trim_end_matches('\0').to_string()
+}
+
+// The optional fields between the frame header and the frame data
+struct FieldData {
+    group_id: Option<u8>,
+
+    // The size of the data once it's decompressed (and unsynchronised in v2.4)
+    data_length: Option<u32>,
+
+    // Where the data is in the frame
+    data: ops::Range<usize>
+}
+
+struct Header {
+    pub frame_id: String,
+    pub size: u64,
+    pub version: u8,
+    pub data_length_indicator: bool,
+    pub unsynch: bool,
+    pub tag_alter_preservation: bool,
+    pub file_alter_preservation: bool,
+    pub read_only: bool,
+    pub compression: bool,
+    pub encryption: bool,
+    pub grouping_ident: bool
+}
+
+impl Header {
+    pub fn default(version: u8) -> Self {
+        Self{
+            frame_id: "".to_string(),
+            size: 0,
+            version,
+            data_length_indicator: false,
+            unsynch: false,
+            tag_alter_preservation: false,
+            file_alter_preservation: false,
+            read_only: false,
+            compression: false,
+            encryption: false,
+            grouping_ident: false,
+        }
+    }
+
+    pub fn flags(&self) -> FrameFlags {
+        FrameFlags{
+            tag_alter_preservation: self.tag_alter_preservation,
+            file_alter_preservation: self.file_alter_preservation,
+            read_only: self.read_only,
+            grouping_ident: self.grouping_ident,
+            compression: self.compression,
+            encryption: self.encryption,
+            unsynch: self.unsynch,
+            data_length_indicator: self.data_length_indicator
+        }
+    }
+
+    pub fn update(&mut self, version: u8) -> bool {
+        match self.frame_id.as_str() {
+            "TORY" => {
+                self.frame_id = "TDOR".to_string();
+            },
+            "TYER" => {
+                self.frame_id = "TDRC".to_string();
+            }
+            "IPLS" => {
+                self.frame_id = "TIPL".to_string();
+            },
+            _ => ()
+        };
+
+        match version {
+            2 => match self.frame_id.as_str() {
+                "CRM" => false,
+                "EQU" => false,
+                "LNK" => false,
+                "RVA" => false,
+                "TSI" => false,
+
+                // Merged into TDRC along with the year once the whole tag has been read
+                "TDA" => {
+                    self.frame_id = "TDAT".to_string();
+                    true
+                },
+                "TIM" => {
+                    self.frame_id = "TIME".to_string();
+                    true
+                },
+                id => {
+                    // v2.2 frames use 3 character ids, which can't be written back out
+                    if let Some(new_id) = upgrade_v22_frame_id(id) {
+                        self.frame_id = new_id.to_string();
+                    }
+                    true
+                }
+            },
+            3 => parsed_in_v23(&self.frame_id),
+            _ => true
+        }
+    }
+}
+
+// RVAD is converted to RVA2, and TDAT and TIME are merged into TDRC, the other frames removed in v2.4 are left unparsed
+fn parsed_in_v23(frame_id: &str) -> bool {
+    matches!(frame_id, "RVAD" | "TDAT" | "TIME") || !V23_ONLY_FRAMES.contains(&frame_id)
+}
+
+// taglib: id3v2frame.cpp:frameTranslation
+fn upgrade_v22_frame_id(frame_id: &str) -> Option<&'static str> {
+    let new_id = match frame_id {
+        "BUF" => "RBUF",
+        "CNT" => "PCNT",
+        "COM" => "COMM",
+        "CRA" => "AENC",
+        "ETC" => "ETCO",
+        "GEO" => "GEOB",
+        "IPL" => "TIPL",
+        "MCI" => "MCDI",
+        "MLL" => "MLLT",
+        "PIC" => "APIC",
+        "POP" => "POPM",
+        "REV" => "RVRB",
+        "SLT" => "SYLT",
+        "STC" => "SYTC",
+        "TAL" => "TALB",
+        "TBP" => "TBPM",
+        "TCM" => "TCOM",
+        "TCO" => "TCON",
+        "TCP" => "TCMP",
+        "TCR" => "TCOP",
+        "TDY" => "TDLY",
+        "TEN" => "TENC",
+        "TFT" => "TFLT",
+        "TKE" => "TKEY",
+        "TLA" => "TLAN",
+        "TLE" => "TLEN",
+        "TMT" => "TMED",
+        "TOA" => "TOPE",
+        "TOF" => "TOFN",
+        "TOL" => "TOLY",
+        "TOR" => "TDOR",
+        "TOT" => "TOAL",
+        "TP1" => "TPE1",
+        "TP2" => "TPE2",
+        "TP3" => "TPE3",
+        "TP4" => "TPE4",
+        "TPA" => "TPOS",
+        "TPB" => "TPUB",
+        "TRC" => "TSRC",
+        "TRD" => "TDRC",
+        "TRK" => "TRCK",
+        "TS2" => "TSO2",
+        "TSA" => "TSOA",
+        "TSC" => "TSOC",
+        "TSP" => "TSOP",
+        "TSS" => "TSSE",
+        "TST" => "TSOT",
+        "TT1" => "TIT1",
+        "TT2" => "TIT2",
+        "TT3" => "TIT3",
+        "TXT" => "TEXT",
+        "TXX" => "TXXX",
+        "TYE" => "TDRC",
+        "UFI" => "UFID",
+        "ULT" => "USLT",
+        "WAF" => "WOAF",
+        "WAR" => "WOAR",
+        "WAS" => "WOAS",
+        "WCM" => "WCOM",
+        "WCP" => "WCOP",
+        "WPB" => "WPUB",
+        "WXX" => "WXXX",
+        _ => return None
+    };
+
+    Some(new_id)
+}
+
+// Unparsed frames from other programs are written into this frame when their id doesn't exist in the
+// version being written, so they survive until the tag is written in a version that has them
+const PASSTHROUGH_ID: &str = "XXXX";
+
+// ID3v2.3 frames which were removed in ID3v2.4
+// TYER, TDAT and TIME become TDRC, TORY becomes TDOR and RVAD becomes RVA2 when the tag is read
+// EQUA (its replacement EQU2 uses a different format), TRDA and TSIZ have no ID3v2.4 equivalent, they're
+// kept in the tag and written to v2.3 tags, but are dropped from v2.4 tags
+const V23_ONLY_FRAMES: [&str; 6] = ["EQUA", "RVAD", "TDAT", "TIME", "TRDA", "TSIZ"];
+
+// Frames which have no ID3v2.3 equivalent are kept in a passthrough frame when writing v2.3 tags
+fn downgrade_frame_id(frame_id: &str, version: u8) -> Option<&str> {
+    if version > 3 {
+        return Some(frame_id);
+    }
+
+    match frame_id {
+        "TDOR" => Some("TORY"),
+        "TDRC" => Some("TYER"),
+        "TIPL" => Some("IPLS"),
+        "ASPI" | "EQU2" | "RVA2" | "SEEK" | "SIGN" | "TDEN" | "TDRL" | "TDTG"
+            | "TMOO" | "TPRO" | "TSOA" | "TSOP" | "TSOT" | "TSST" => None,
+        id => Some(id)
+    }
+}
+
+pub(crate) fn sizeof_frame_header(version: u8) -> u64 {
+    if version < 3 {
+        6
+    } else {
+        10
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag_header(version: u8) -> tag::TagHeader {
+        tag::TagHeader{
+            major_version: version,
+            rev_num: 0,
+            size: 0,
+            unsynch: false,
+            extended: false,
+            experimental: false,
+            footer: false,
+            extended_header: None
+        }
+    }
+
+    #[test]
+    fn v23_only_frames_are_dropped_from_v24() {
+        let mut buf = b"TSIZ\0\0\0\x05\0\0\x001024".to_vec();
+        let original = buf.clone();
+        let frame = Frame::from_buffer(&mut buf, &tag_header(3)).unwrap().unwrap();
+        assert_eq!(frame.frame_id, "TSIZ");
+
+        assert!(frame.render(4, false).is_none());
+        assert_eq!(frame.render(3, false).unwrap(), original);
+    }
+
+    #[test]
+    fn unmapped_v22_frames_pass_through() {
+        let mut buf = b"XYZ\0\0\x03abc".to_vec();
+        let frame = Frame::from_buffer(&mut buf, &tag_header(2)).unwrap().unwrap();
+
+        let mut rendered = frame.render(4, false).unwrap();
+        assert_eq!(&rendered[..4], b"XXXX");
+
+        let frame = Frame::from_buffer(&mut rendered, &tag_header(4)).unwrap().unwrap();
+        assert_eq!(frame.frame_id, "XYZ");
+        assert_eq!(frame.version, 2);
+        assert_eq!(frame.data, b"abc");
+    }
+
+    #[test]
+    fn v22_pictures() {
+        for (format, mime_type) in &[(b"JPG", "image/jpeg"), (b"PNG", "image/png")] {
+            let mut buf = b"PIC\0\0\x0f\0".to_vec();
+            buf.extend_from_slice(*format);
+            buf.extend_from_slice(b"\x03Cover\0data");
+            let frame = Frame::from_buffer(&mut buf, &tag_header(2)).unwrap().unwrap();
+            assert_eq!(frame.frame_id, "APIC");
+
+            let picture = match frame.sub {
+                SubClass::Picture(ref picture) => picture.clone(),
+                _ => panic!("PIC wasn't parsed")
+            };
+            assert_eq!(picture.mime_type, *mime_type);
+            assert_eq!(picture.picture_type, PictureType::FrontCover);
+            assert_eq!(picture.description, "Cover");
+            assert_eq!(picture.data, b"data");
+
+            // The image format is written as a MIME type from then on
+            let mut apic = frame.render(4, false).unwrap();
+            assert_eq!(&apic[..4], b"APIC");
+            let frame = Frame::from_buffer(&mut apic, &tag_header(4)).unwrap().unwrap();
+            match frame.sub {
+                SubClass::Picture(ref parsed) => assert_eq!(parsed.mime_type, *mime_type),
+                _ => panic!("APIC wasn't parsed")
+            }
+        }
+    }
+
+    // Parse a v2.4 frame from its id and body
+    fn parse_frame(frame_id: &[u8], body: &[u8]) -> Frame {
+        let mut buf = frame_id.to_vec();
+        buf.extend_from_slice(&tag::synch::int_to_buf(body.len() as u32));
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(body);
+        Frame::from_buffer(&mut buf, &tag_header(4)).unwrap().unwrap()
+    }
+
+    #[test]
+    fn popularimeter_counters() {
+        // The counter is optional
+        let frame = parse_frame(b"POPM", b"a@b.c\0\xc4");
+        let popm = match frame.sub {
+            SubClass::Popularimeter(ref popm) => popm.clone(),
+            _ => panic!("POPM wasn't parsed")
+        };
+        assert_eq!((popm.email.as_str(), popm.rating, popm.counter), ("a@b.c", 196, 0));
+        assert_eq!(popm.stars(), 4);
+
+        // Counters grow past 4 bytes when needed
+        let body = b"a@b.c\0\x40\x01\0\0\0\x02";
+        let frame = parse_frame(b"POPM", body);
+        match frame.sub {
+            SubClass::Popularimeter(ref popm) => assert_eq!((popm.rating, popm.counter), (64, 0x1_0000_0002)),
+            _ => panic!("POPM wasn't parsed")
+        }
+        assert_eq!(&frame.render(4, false).unwrap()[10..], body);
+
+        let mut popm = Popularimeter::new("a@b.c", 0, 3);
+        popm.set_stars(5);
+        assert_eq!(&Frame::new("POPM", SubClass::Popularimeter(popm)).render(4, false).unwrap()[10..], b"a@b.c\0\xff\0\0\0\x03");
+    }
+
+    #[test]
+    fn play_counters() {
+        for (body, count) in &[(&b"\0\0\0\x07"[..], 7), (&b"\x01\0\0\0\0"[..], 0x1_0000_0000)] {
+            let frame = parse_frame(b"PCNT", body);
+            assert!(matches!(frame.sub, SubClass::PlayCount(parsed) if parsed == *count));
+            assert_eq!(&frame.render(4, false).unwrap()[10..], *body);
+        }
+
+        // Counters too wide for a u64 are capped
+        let frame = parse_frame(b"PCNT", &[1; 9]);
+        assert!(matches!(frame.sub, SubClass::PlayCount(u64::MAX)));
+    }
+
+    #[test]
+    fn unique_file_ids() {
+        let body = b"http://musicbrainz.org\0id\0with nulls";
+        let frame = parse_frame(b"UFID", body);
+        match frame.sub {
+            SubClass::UniqueFileId(ref ufid) => {
+                assert_eq!(ufid.owner, "http://musicbrainz.org");
+                assert_eq!(ufid.identifier, b"id\0with nulls");
+            },
+            _ => panic!("UFID wasn't parsed")
+        }
+        assert_eq!(&frame.render(4, false).unwrap()[10..], &body[..]);
+
+        // An owner is required, frames without one are kept as they are
+        let frame = parse_frame(b"UFID", b"\0id");
+        assert!(matches!(frame.sub, SubClass::Unknown));
+        assert_eq!(&frame.render(4, false).unwrap()[10..], b"\0id");
+    }
+
+    #[test]
+    fn objects_private_ownership_and_podcast() {
+        let geob = b"\0text/plain\0notes.txt\0Notes\0some\0text";
+        let frame = parse_frame(b"GEOB", geob);
+        match frame.sub {
+            SubClass::GeneralObject(ref object) => {
+                assert_eq!((object.mime_type.as_str(), object.filename.as_str()), ("text/plain", "notes.txt"));
+                assert_eq!(object.description, "Notes");
+                assert_eq!(object.data, b"some\0text");
+            },
+            _ => panic!("GEOB wasn't parsed")
+        }
+        assert_eq!(&frame.render(4, false).unwrap()[10..], &geob[..]);
+
+        let private = b"WM/MediaClassPrimaryID\0\xbc\x7d\x60\xd1";
+        let frame = parse_frame(b"PRIV", private);
+        match frame.sub {
+            SubClass::Private(ref private) => {
+                assert_eq!(private.owner, "WM/MediaClassPrimaryID");
+                assert_eq!(private.data, vec![0xbc, 0x7d, 0x60, 0xd1]);
+            },
+            _ => panic!("PRIV wasn't parsed")
+        }
+        assert_eq!(&frame.render(4, false).unwrap()[10..], &private[..]);
+
+        let owne = b"\0USD10.99\x0020200131Record Store";
+        let frame = parse_frame(b"OWNE", owne);
+        match frame.sub {
+            SubClass::Ownership(ref ownership) => {
+                assert_eq!(ownership.price_paid, "USD10.99");
+                assert_eq!(ownership.purchase_date, "20200131");
+                assert_eq!(ownership.seller, "Record Store");
+            },
+            _ => panic!("OWNE wasn't parsed")
+        }
+        assert_eq!(&frame.render(4, false).unwrap()[10..], &owne[..]);
+
+        // The purchase date is required
+        assert!(matches!(parse_frame(b"OWNE", b"\0USD1\x002020").sub, SubClass::Unknown));
+
+        let frame = parse_frame(b"PCST", b"\0\0\0\0");
+        assert!(matches!(frame.sub, SubClass::Podcast(0)));
+        assert_eq!(&frame.render(4, false).unwrap()[10..], b"\0\0\0\0");
+        assert!(matches!(parse_frame(b"PCST", b"\0\0").sub, SubClass::Unknown));
+    }
+
+    #[test]
+    fn inflate_deflate() {
+        let data = vec![b'a'; 1000];
+        let compressed = deflate(&data).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(inflate(&compressed, 1000).unwrap(), data);
+
+        // Reading stops just past the declared size
+        assert_eq!(inflate(&compressed, 10).unwrap().len(), 11);
+        assert!(inflate(b"not zlib", 10).is_none());
+    }
+
+    #[test]
+    fn compressed_frames() {
+        let mut frame = Frame::new("TIT2", SubClass::Text(vec!["a".repeat(200)], StringType::Latin1));
+        frame.group_id = Some(7);
+
+        for version in 3..5 {
+            let mut buf = frame.render(version, true).unwrap();
+            let parsed = Frame::from_buffer(&mut buf, &tag_header(version)).unwrap().unwrap();
+            assert!(parsed.flags.compression);
+            assert_eq!(parsed.group_id, Some(7));
+            assert_eq!(parsed.data, frame.data);
+
+            // The decompressed size follows the group id in v2.4 and comes before it in v2.3
+            let size_at = if version == 3 { 10 } else { 11 };
+            buf[size_at + 3] -= 1;
+            match Frame::from_buffer(&mut buf, &tag_header(version)) {
+                Err(Error::Malformed{ offset, .. }) => assert_eq!(offset, 15),
+                _ => panic!("Mismatched decompressed size was accepted")
+            }
+        }
+    }
+
+    #[test]
+    fn rvad_to_rva2() {
+        // Right +6dB (factor 1.995), left -6dB (factor 0.501), no peaks
+        let mut buf = b"RVAD\0\0\0\x0a\0\0\x01\x10\xfe\xc9\x7f\xb2\0\0\0\0".to_vec();
+        let frame = Frame::from_buffer(&mut buf, &tag_header(3)).unwrap().unwrap();
+        assert_eq!(frame.frame_id, "RVA2");
+
+        let volume = match frame.sub {
+            SubClass::RelativeVolume(ref volume) => volume.clone(),
+            _ => panic!("RVAD wasn't parsed")
+        };
+        assert!((volume.channel(ChannelType::FrontRight).unwrap().gain - 6.0).abs() < 0.01);
+        assert!((volume.channel(ChannelType::FrontLeft).unwrap().gain + 6.0).abs() < 0.01);
+
+        let mut rva2 = frame.render(4, false).unwrap();
+        assert_eq!(&rva2[..4], b"RVA2");
+        let frame = Frame::from_buffer(&mut rva2, &tag_header(4)).unwrap().unwrap();
+        assert_eq!(frame.render(3, false).unwrap(), buf);
+    }
+
+    #[test]
+    fn rva2_master_to_rvad() {
+        let volume = RelativeVolume::new("track", vec![ChannelAdjustment{ channel: ChannelType::Master, gain: -3.0, peak: 0.5 }]);
+        let rvad = RelativeVolume::parse_rvad(&volume.render_rvad()).unwrap();
+
+        assert_eq!(rvad.channels.len(), 2);
+        for channel in &rvad.channels {
+            assert!((channel.gain + 3.0).abs() < 0.01);
+            assert!((channel.peak - 0.5).abs() < 0.001);
+        }
+    }
+
+    #[test]
+    fn utf16_byte_order() {
+        assert_eq!(decode_text(&[0xff, 0xfe, b'A', 0, b'B', 0], &StringType::UTF16), "AB");
+        assert_eq!(decode_text(&[0xfe, 0xff, 0, b'A', 0, b'B'], &StringType::UTF16), "AB");
+        assert_eq!(decode_text(&[0, b'A', 0, b'B', 0, 0], &StringType::UTF16), "AB");
+        assert_eq!(decode_text(&[0, b'A', 0, b'B'], &StringType::UTF16be), "AB");
+        assert_eq!(decode_text(&[b'A', 0, b'B', 0], &StringType::UTF16le), "AB");
+    }
+
+    #[test]
+    fn utf16_text_inherits_description_byte_order() {
+        // Little endian description "D", followed by text without a byte order mark
+        let strings = [1, 0xff, 0xfe, b'D', 0, 0, 0, b'v', 0];
+        let user_text = UserText::parse(&strings).unwrap();
+        assert_eq!(user_text.description, "D");
+        assert_eq!(user_text.values, vec!["v"]);
+
+        let comment = [&[1, b'e', b'n', b'g'][..], &strings[1..]].concat();
+        let (_, language, description, text) = parse_language_text(&comment).unwrap();
+        assert_eq!((language.as_str(), description.as_str(), text.as_str()), ("eng", "D", "v"));
+
+        let lyrics = [&[1, b'e', b'n', b'g', 2, 1][..], &strings[1..], &[0, 0, 0, 0, 0, 5]].concat();
+        let lyrics = SyncedLyrics::parse(&lyrics).unwrap();
+        assert_eq!(lyrics.lines, vec![(5, "v".to_string())]);
+    }
+
+    #[test]
+    fn utf16_values_inherit_byte_order() {
+        // The second value has no byte order mark, the third has its own
+        let buf = [0xff, 0xfe, b'A', 0, 0, 0, b'B', 0, 0, 0, 0xfe, 0xff, 0, b'C'];
+        assert_eq!(split_values(&buf, &StringType::UTF16), vec!["A", "B", "C"]);
+
+        let buf = [0xfe, 0xff, 0, b'A', 0, 0, 0xff, 0xfe, b'B', 0, 0, 0, 0, b'C'];
+        assert_eq!(split_values(&buf, &StringType::UTF16), vec!["A", "B", "\u{4300}"]);
+    }
+
+    #[test]
+    fn lrc_import() {
+        let lrc = "[ar:Artist]\n[00:01.50]First\n[01:02.345][00:00.1]Repeated\n[99999:00.00]Too late\nNo time";
+        let lyrics = SyncedLyrics::from_lrc("eng", "", lrc);
+
+        assert_eq!(lyrics.timestamp_format, TimestampFormat::Milliseconds);
+        assert_eq!(lyrics.lines, vec![
+            (100, "Repeated".to_string()),
+            (1500, "First".to_string()),
+            (62345, "Repeated".to_string())
+        ]);
+    }
+
+    #[test]
+    fn lrc_time_overflow() {
+        assert_eq!(parse_lrc_time("71582:47.295"), Some(u32::MAX));
+        assert_eq!(parse_lrc_time("71582:47.296"), None);
+        assert_eq!(parse_lrc_time("99999:00.00"), None);
+        assert_eq!(parse_lrc_time("0:4294968.00"), None);
+    }
+
+    #[test]
+    fn lrc_export() {
+        let lyrics = SyncedLyrics::new("eng", "", vec![(1500, "First".to_string()), (62345, "Second".to_string())]);
+        assert_eq!(lyrics.to_lrc().unwrap(), "[00:01.50]First\n[01:02.34]Second\n");
+
+        let mut frames = lyrics.clone();
+        frames.timestamp_format = TimestampFormat::MpegFrames;
+        assert_eq!(frames.to_lrc(), None);
+    }
+
+    #[test]
+    fn sylt_round_trip() {
+        let lyrics = SyncedLyrics::from_lrc("eng", "desc", "[00:01.00]One\n[00:02.00]Two");
+
+        for version in 3..5 {
+            let parsed = SyncedLyrics::parse(&lyrics.render(version)).unwrap();
+            assert_eq!(parsed.language, "eng");
+            assert_eq!(parsed.description, "desc");
+            assert_eq!(parsed.lines, lyrics.lines);
+        }
     }
 }
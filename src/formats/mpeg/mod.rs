@@ -10,3 +10,4 @@ mod file;
 pub use self::file::*;
 
 mod frame;
+pub use self::frame::{ChannelAdjustment, ChannelType, Chapter, Comment, Frame, FrameFlags, GeneralObject, LinkType, Lyrics, Ownership, Picture, PictureType, Popularimeter, Private, RelativeVolume, StringType, SubClass, SyncedLyrics, TableOfContents, TimestampFormat, UniqueFileId, UserLink, UserText};
@@ -1,244 +1,1239 @@
 #![allow(dead_code)]
 #![allow(unused_imports)]
 
+use formats::error::Error;
 use formats::meta;
 use formats::utils;
 use super::frame;
 
+use std::cmp::min;
 use std::collections::HashMap;
+use std::convert;
 use std::fs;
-use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+use std::io::{Read, Seek, SeekFrom};
 use std::mem;
+use std::ptr;
 use std::rc;
 
 use byteorder::{BigEndian, ByteOrder};
 
 
+#[derive(Clone, Default)]
 pub struct Tag {
-    frame_map: HashMap<String, frame::SubClass>,
+    header: Option<TagHeader>,
+    frame_map: HashMap<String, Vec<frame::Frame>>,
+
+    // Frames at least this large are compressed when writing
+    compression_threshold: Option<usize>,
 }
 
 impl meta::Tag for Tag {
     fn title(&self) -> Option<String> {
-        if let Some(frame::SubClass::Text(title, _)) = self.frame_map.get("TIT2") {
-            Some(title.to_string())
-        } else {
-            None
-        }
+        self.text("TIT2")
     }
     fn artist(&self) -> Option<String> {
-        if let Some(frame::SubClass::Text(artist, _)) = self.frame_map.get("TPE1") {
-            Some(artist.to_string())
-        } else {
-            None
-        }
+        self.text("TPE1")
     }
     fn album(&self) -> Option<String> {
-        if let Some(frame::SubClass::Text(album, _)) = self.frame_map.get("TALB") {
-            Some(album.to_string())
-        } else {
-            None
-        }
+        self.text("TALB")
     }
     fn year(&self) -> Option<u64> {
-        if let Some(frame::SubClass::Uint(year)) = self.frame_map.get("TDRC") {
-            Some(*year)
-        } else {
-            None
+        match self.get("TDRC") {
+            Some(frame::SubClass::Uint(year)) => Some(*year),
+
+            // Timestamps are formatted as "yyyy-MM-ddTHH:mm:ss", with everything past the year optional
+            Some(frame::SubClass::Text(date, _)) => date.first()?.get(0..4).and_then(|year| year.parse().ok()),
+            _ => None
         }
     }
+    // Comments with a description are usually data from other programs (eg. "iTunNORM")
     fn comment(&self) -> Option<String> {
-        if let Some(frame::SubClass::Text(comment, _)) = self.frame_map.get("COMM") {
-            Some(comment.to_string())
-        } else {
-            None
-        }
+        self.find_comment(None, "")
+            .or_else(|| self.comments().into_iter().find(|comment| !comment.description.starts_with("iTun")))
+            .map(|comment| comment.text.to_string())
     }
     fn track(&self) -> Option<u32> {
-        if let Some(frame::SubClass::Uint(track)) = self.frame_map.get("TRCK") {
-            Some(*track as u32)
-        } else {
-            None
+        match self.get("TRCK") {
+            Some(frame::SubClass::Uint(track)) => Some(*track as u32),
+
+            // Tracks may be formatted as "track/total"
+            Some(frame::SubClass::Text(track, _)) => track.first()?.split('/').next().and_then(|track| track.trim().parse().ok()),
+            _ => None
         }
     }
 
-    // TODO: This needs to be built up when I construct the tag
     fn genre(&self) -> Option<String> {
-        if let Some(frame::SubClass::Text(genre, _)) = self.frame_map.get("TCON") {
-            Some(genre.to_string())
-        } else {
+        let genres = self.genres();
+        if genres.is_empty() {
             None
+        } else {
+            Some(genres.join("/"))
+        }
+    }
+
+    fn set_title(&mut self, title: String) -> bool {
+        self.set_text("TIT2", title);
+        true
+    }
+    fn set_artist(&mut self, artist: String) -> bool {
+        self.set_text("TPE1", artist);
+        true
+    }
+    fn set_album(&mut self, album: String) -> bool {
+        self.set_text("TALB", album);
+        true
+    }
+    fn set_year(&mut self, year: u64) -> bool {
+        self.insert("TDRC", frame::SubClass::Uint(year));
+        true
+    }
+    fn set_comment(&mut self, comment: String) -> bool {
+        // Only the comment which `comment()` returns is replaced, comments in other languages are kept
+        let language = self.find_comment(None, "").map_or("XXX".to_string(), |comment| comment.language.to_string());
+        self.set_comment_frame(frame::Comment::new(&language, "", &comment));
+        true
+    }
+    fn set_track(&mut self, track: u32) -> bool {
+        // Keep the total number of tracks if it was specified
+        let total = match self.get("TRCK") {
+            Some(frame::SubClass::Text(track, _)) => track.first().and_then(|track| track.split('/').nth(1)).map(|total| total.to_string()),
+            _ => None
+        };
+
+        match total {
+            Some(total) => self.set_text("TRCK", format!("{}/{}", track, total)),
+            None => self.insert("TRCK", frame::SubClass::Uint(track as u64))
+        }
+        true
+    }
+    fn set_genre(&mut self, genre: String) -> bool {
+        self.set_text("TCON", genre);
+        true
+    }
+
+    fn remove_title(&mut self) {
+        self.remove("TIT2");
+    }
+    fn remove_artist(&mut self) {
+        self.remove("TPE1");
+    }
+    fn remove_album(&mut self) {
+        self.remove("TALB");
+    }
+    fn remove_year(&mut self) {
+        self.remove("TDRC");
+    }
+    fn remove_comment(&mut self) {
+        self.remove_comments(None, "");
+    }
+    fn remove_track(&mut self) {
+        self.remove("TRCK");
+    }
+    fn remove_genre(&mut self) {
+        self.remove("TCON");
+    }
+
+    fn properties(&self) -> meta::PropertyMap {
+        let mut properties = meta::PropertyMap::new();
+
+        for (key, frame_id) in PROPERTY_FRAMES.iter() {
+            let values = match self.get(frame_id) {
+                Some(frame::SubClass::Text(_, _)) if *frame_id == "TCON" => self.genres(),
+                Some(frame::SubClass::Text(values, _)) if !values.is_empty() => values.clone(),
+                Some(frame::SubClass::Uint(value)) => vec![value.to_string()],
+                Some(frame::SubClass::Comment(_)) => match meta::Tag::comment(self) {
+                    Some(comment) => vec![comment],
+                    None => continue
+                },
+                Some(frame::SubClass::Lyrics(_)) => match self.all_lyrics().into_iter().find(|lyrics| lyrics.description.is_empty()) {
+                    Some(lyrics) => vec![lyrics.text.to_string()],
+                    None => continue
+                },
+                _ => continue
+            };
+
+            properties.insert(key.to_string(), values);
+        }
+
+        if let Some(id) = self.musicbrainz_recording_id() {
+            properties.insert("MUSICBRAINZ_TRACKID".to_string(), vec![id]);
+        }
+
+        // Anything without its own frame is stored in TXXX, keyed by the description
+        for text in self.user_texts() {
+            let key = PROPERTY_USER_TEXTS.iter()
+                .find(|(_, description)| *description == text.description)
+                .map_or(text.description.to_uppercase(), |(key, _)| key.to_string());
+            properties.entry(key).or_insert_with(|| text.values.clone());
+        }
+
+        properties
+    }
+
+    fn set_property(&mut self, key: &str, values: Vec<String>) -> bool {
+        let key = key.to_uppercase();
+        let key = key.as_str();
+
+        let frame_id = match PROPERTY_FRAMES.iter().find(|(k, _)| *k == key) {
+            Some((_, frame_id)) => *frame_id,
+            None if key == "MUSICBRAINZ_TRACKID" => {
+                match values.first() {
+                    Some(id) => self.set_musicbrainz_recording_id(id),
+                    None => self.remove_unique_file_id(MUSICBRAINZ_OWNER)
+                }
+                return true;
+            },
+            None if !valid_user_text_key(key) => return false,
+            None => {
+                // Keep the case of the existing description
+                let description = match PROPERTY_USER_TEXTS.iter().find(|(k, _)| *k == key) {
+                    Some((_, description)) => description.to_string(),
+                    None => self.user_texts().into_iter()
+                        .find(|text| text.description.eq_ignore_ascii_case(key))
+                        .map_or(key.to_string(), |text| text.description.to_string())
+                };
+
+                self.remove_user_text(&description);
+                if !values.is_empty() {
+                    self.push("TXXX", frame::SubClass::UserText(frame::UserText::new(&description, values)));
+                }
+                return true;
+            }
+        };
+
+        if frame_id == "COMM" && values.is_empty() {
+            meta::Tag::remove_comment(self);
+        } else if frame_id == "COMM" {
+            meta::Tag::set_comment(self, values.join("\n"));
+        } else if frame_id == "USLT" && values.is_empty() {
+            self.retain("USLT", |sub| match sub {
+                frame::SubClass::Lyrics(lyrics) => !lyrics.description.is_empty(),
+                _ => true
+            });
+        } else if frame_id == "USLT" {
+            // Only the lyrics without a description are replaced, in the language they already had
+            let language = self.all_lyrics().into_iter()
+                .find(|lyrics| lyrics.description.is_empty())
+                .map_or("XXX".to_string(), |lyrics| lyrics.language.to_string());
+            self.set_lyrics(frame::Lyrics::new(&language, "", &values.join("\n")));
+        } else if values.is_empty() {
+            self.remove(frame_id);
+        } else {
+            self.set_text_values(frame_id, values);
         }
+
+        true
     }
 }
 
+// Property keys and the frames they're stored in
+static PROPERTY_FRAMES: [(&str, &str); 48] = [
+    ("TITLE", "TIT2"),
+    ("SUBTITLE", "TIT3"),
+    ("GROUPING", "TIT1"),
+    ("ARTIST", "TPE1"),
+    ("ALBUMARTIST", "TPE2"),
+    ("CONDUCTOR", "TPE3"),
+    ("REMIXER", "TPE4"),
+    ("ALBUM", "TALB"),
+    ("COMMENT", "COMM"),
+    ("GENRE", "TCON"),
+    ("DATE", "TDRC"),
+    ("ORIGINALDATE", "TDOR"),
+    ("RELEASEDATE", "TDRL"),
+    ("TAGGINGDATE", "TDTG"),
+    ("ENCODINGTIME", "TDEN"),
+    ("COMPOSER", "TCOM"),
+    ("LYRICIST", "TEXT"),
+    ("ENCODEDBY", "TENC"),
+    ("ENCODING", "TSSE"),
+    ("COPYRIGHT", "TCOP"),
+    ("PRODUCEDNOTICE", "TPRO"),
+    ("LABEL", "TPUB"),
+    ("TRACKNUMBER", "TRCK"),
+    ("DISCNUMBER", "TPOS"),
+    ("DISCSUBTITLE", "TSST"),
+    ("COMPILATION", "TCMP"),
+    ("BPM", "TBPM"),
+    ("INITIALKEY", "TKEY"),
+    ("LANGUAGE", "TLAN"),
+    ("LENGTH", "TLEN"),
+    ("MEDIA", "TMED"),
+    ("MOOD", "TMOO"),
+    ("ISRC", "TSRC"),
+    ("MOVEMENTNAME", "MVNM"),
+    ("ORIGINALALBUM", "TOAL"),
+    ("ORIGINALARTIST", "TOPE"),
+    ("ORIGINALLYRICIST", "TOLY"),
+    ("ORIGINALFILENAME", "TOFN"),
+    ("OWNER", "TOWN"),
+    ("RADIOSTATION", "TRSN"),
+    ("RADIOSTATIONOWNER", "TRSO"),
+    ("TITLESORT", "TSOT"),
+    ("ARTISTSORT", "TSOP"),
+    ("ALBUMSORT", "TSOA"),
+    ("ALBUMARTISTSORT", "TSO2"),
+    ("COMPOSERSORT", "TSOC"),
+    ("PLAYLISTDELAY", "TDLY"),
+    ("LYRICS", "USLT"),
+];
+
+// Property keys and the TXXX descriptions they're stored under
+// MUSICBRAINZ_TRACKID is stored in the MusicBrainz UFID frame
+static PROPERTY_USER_TEXTS: [(&str, &str); 9] = [
+    ("MUSICBRAINZ_ARTISTID", "MusicBrainz Artist Id"),
+    ("MUSICBRAINZ_ALBUMID", "MusicBrainz Album Id"),
+    ("MUSICBRAINZ_ALBUMARTISTID", "MusicBrainz Album Artist Id"),
+    ("MUSICBRAINZ_RELEASEGROUPID", "MusicBrainz Release Group Id"),
+    ("MUSICBRAINZ_RELEASETRACKID", "MusicBrainz Release Track Id"),
+    ("MUSICBRAINZ_WORKID", "MusicBrainz Work Id"),
+    ("ASIN", "ASIN"),
+    ("CATALOGNUMBER", "CATALOGNUMBER"),
+    ("BARCODE", "BARCODE"),
+];
+
+static MUSICBRAINZ_OWNER: &str = "http://musicbrainz.org";
+
 impl Tag {
     // TODO: Improve the process for unifying id3v1 and id3v2 tags
     pub fn unify(tags: Vec<rc::Rc<Self>>) -> Self {
         let mut ret_tag = Self::default();
 
         for tag in tags {
-            for (key, value) in &tag.frame_map {
+            if ret_tag.header.is_none() {
+                ret_tag.header = tag.header.clone();
+            }
 
-                use std::collections::hash_map::Entry::*;
-                match ret_tag.frame_map.entry(key.to_string()) {
-                    // TODO: Temporary workaround for bad utf16 parsing (see frame.rs)
-                    Occupied(mut ent) => {
-                        match ent.get() {
-                            frame::SubClass::Text(_, frame::StringType::UTF16) => { ent.insert(value.clone()); },
-                            _ => ()
-                        };
-                    },
-                    Vacant(ent) => { ent.insert(value.clone()); },
-                };
+            // The ID3v2 tag comes first, so the ID3v1 tag only fills in missing fields
+            for (key, value) in &tag.frame_map {
+                ret_tag.frame_map.entry(key.to_string()).or_insert_with(|| value.clone());
             }
         }
 
         ret_tag
     }
 
-    pub fn id3v2_from_file(file: &mut fs::File, offset: u64) -> Result<Self, Error> {
+    pub fn id3v2_from_file<R: Read + Seek>(file: &mut R, offset: u64) -> Result<Self, Error> {
         file.seek(SeekFrom::Start(offset))?;
 
         let mut header = vec![0; 10];
         file.read_exact(&mut header)?;
 
-        let header = parse_tag_header(&header)?;
+        let header = parse_tag_header(&header).map_err(|err| err.offset_by(offset))?;
         if header.size != 0 {
             let mut buf = vec![0; header.size as usize];
             file.read_exact(&mut buf)?;
-            return Tag::from_buffer(&mut buf, &header);
+            return Tag::from_buffer(&mut buf, &header).map_err(|err| err.offset_by(offset + 10));
 
         }
 
         // TODO: This could only be causing us to "skip" some stuff, not fail at parsing
         // NOTE: Taglib has some stuff about ignoring duplicate flags (I'm ignoring that for now)
 
-        Err(Error::new(ErrorKind::InvalidData, "Tags must contain at least 1 frame"))
+        Err(Error::malformed(offset, "ID3", "Tags must contain at least 1 frame"))
     }
 
-    pub fn id3v1_from_file(file: &mut fs::File, offset: u64) -> Result<Self, Error> {
+    pub fn id3v1_from_file<R: Read + Seek>(file: &mut R, offset: u64) -> Result<Self, Error> {
         file.seek(SeekFrom::Start(offset))?;
 
         let mut block = vec![0; 128];
         file.read_exact(&mut block)?;
 
-        let mut tag = Tag{ frame_map: HashMap::new() };
+        let mut tag = Tag::default();
 
-        use self::frame::StringType;
-        tag.frame_map.insert("TIT2".to_string(), frame::SubClass::Text(utils::from_ascii(&block[3..33]), StringType::UTF8));
-        tag.frame_map.insert("TPE1".to_string(), frame::SubClass::Text(utils::from_ascii(&block[33..63]), StringType::UTF8));
-        tag.frame_map.insert("TALB".to_string(), frame::SubClass::Text(utils::from_ascii(&block[63..93]), StringType::UTF8));
-        // tag.frame_map.insert("TDRC".to_string(), frame::SubClass::Uint(&block[93..97]));
+        // Empty fields are left out so they don't hide the ID3v2 values when unifying
+        let mut insert_text = |frame_id: &str, buf: &[u8]| {
+            let text = id3v1_text(buf);
+            if !text.is_empty() {
+                tag.set_text(frame_id, text);
+            }
+        };
 
-        if block[125] == 0 && block[126] != 0 {
-            tag.frame_map.insert("COMM".to_string(), frame::SubClass::Text(utils::from_ascii(&block[97..125]), StringType::UTF8));
-            tag.frame_map.insert("TRCK".to_string(), frame::SubClass::Uint(block[126] as u64));
+        insert_text("TIT2", &block[3..33]);
+        insert_text("TPE1", &block[33..63]);
+        insert_text("TALB", &block[63..93]);
+
+        if block[93..97].iter().all(|ch| ch.is_ascii_digit()) {
+            insert_text("TDRC", &block[93..97]);
+        }
+
+        // ID3v1.1 puts the track number in the last 2 bytes of the comment
+        let comment_end = if block[125] == 0 && block[126] != 0 {
+            tag.insert("TRCK", frame::SubClass::Uint(block[126] as u64));
+            125
         } else {
-            tag.frame_map.insert("COMM".to_string(), frame::SubClass::Text(utils::from_ascii(&block[97..127]), StringType::UTF8));
+            127
+        };
+
+        let comment = id3v1_text(&block[97..comment_end]);
+        if !comment.is_empty() {
+            tag.set_comment_frame(frame::Comment::new("XXX", "", &comment));
         }
 
-        // tag.frame_map.insert("TCON".to_string(), frame::SubClass::Uint(block[127] as u64));
+        // Unknown genres are stored as 255
+        if let Some(genre) = meta::genre_name(block[127] as usize) {
+            tag.set_text("TCON", genre.to_string());
+        }
 
         Ok(tag)
 
     }
 
+    // ID3v2.3 tags are unsynchronised as a whole, errors are reported at their position in the original tag
     fn from_buffer(buf: &mut Vec<u8>, header: &TagHeader) -> Result<Self, Error> {
         if header.unsynch && header.major_version <= 3 {
-            let mut tmp_vec = synch::decode(&buf);
-            mem::swap(buf, &mut tmp_vec);
+            let original = mem::replace(buf, synch::decode(buf));
+            return Tag::parse_frames(buf, header).map_err(|err| err.map_offset(|offset| synch::encoded_offset(&original, offset)));
         }
 
+        Tag::parse_frames(buf, header)
+    }
+
+    fn parse_frames(buf: &mut [u8], header: &TagHeader) -> Result<Self, Error> {
         let mut pos = 0;
-        let mut buf_end = buf.len();
-
-        // TODO: Parse extended header
-        let _ext_header = if header.extended {
-            // taglib:id3v2tag.cpp:904
-            /*
-            d->extendedHeader = new ExtendedHeader();
-            d->extendedHeader->setData(data);
-            if(d->extendedHeader->size() <= data.size()) {
-                frameDataPosition += d->extendedHeader->size();
-                frameDataLength -= d->extendedHeader->size();
+        // `header.size` doesn't include the footer, so the whole buffer is frames and padding
+        let buf_end = buf.len();
+
+        let mut header = header.clone();
+        if header.extended {
+            let mut extended = parse_extended_header(buf, header.major_version)?;
+            pos = extended.size as usize;
+
+            // v2.3 checks the frames, v2.4 checks the frames and padding
+            let crc_end = match extended.padding_size {
+                Some(padding) => buf_end.saturating_sub(padding as usize),
+                None => buf_end
+            };
+            if extended.crc.is_some() {
+                extended.computed_crc = Some(crc32fast::hash(&buf[min(pos, crc_end)..crc_end]));
             }
-             */
-            ()
-        };
 
-        if header.footer && sizeof_footer() <= buf_end {
-            buf_end -= sizeof_footer();
+            header.extended_header = Some(extended);
         }
+        let header = &header;
 
-        let mut frame_map = HashMap::new();
-        while pos < buf_end - frame::sizeof_frame_header(header.major_version) as usize {
+        let mut frame_map: HashMap<String, Vec<frame::Frame>> = HashMap::new();
+        while pos + (frame::sizeof_frame_header(header.major_version) as usize) < buf_end {
             if buf[pos] == 0 {
                 if header.footer {
-                    return Err(Error::new(ErrorKind::InvalidData, "Padding and footers are not allowed by the spec"));
+                    return Err(Error::malformed(pos as u64, "ID3", "Padding and footers are not allowed by the spec"));
                 }
 
                 break;
             }
 
-            let mut new_frame = match frame::Frame::from_buffer(&mut buf[pos..], &header)? {
+            let new_frame = match frame::Frame::from_buffer(&mut buf[pos..], header).map_err(|err| err.offset_by(pos as u64))? {
                 Some(frame) => frame,
                 None => break
             };
             if new_frame.size == 0 {
                 break;
-                // return Err(Error::new(ErrorKind::InvalidData, "Found size 0 frame"));
-            }
-
-            // Convert integer frames to an expected format
-            match new_frame.frame_id.as_str() {
-                "TDRC" => {
-                    new_frame.sub = frame::SubClass::Uint(0);
-                }
-                "TRCK" => {
-                    new_frame.sub = frame::SubClass::Uint(0);
-                }
-
-                _ => ()
+                // return Err(Error::malformed(pos as u64, &new_frame.frame_id, "Found size 0 frame"));
             }
 
             let size = new_frame.size + frame::sizeof_frame_header(header.major_version) as usize;
             pos += size;
-            frame_map.insert(new_frame.frame_id.to_string(), new_frame.sub);
+            frame_map.entry(new_frame.frame_id.to_string()).or_default().push(new_frame);
+        }
+
+        if header.major_version < 4 {
+            merge_dates(&mut frame_map);
         }
 
         Ok(Tag{
-            frame_map: frame_map
+            header: Some(header.clone()),
+            frame_map,
+            compression_threshold: None
+        })
+    }
+
+    // The header of the ID3v2 tag this was read from
+    pub fn header(&self) -> Option<&TagHeader> {
+        self.header.as_ref()
+    }
+
+    // Every frame in the tag, ordered by frame id
+    pub fn frames(&self) -> Vec<&frame::Frame> {
+        let mut frame_ids = self.frame_map.keys().collect::<Vec<_>>();
+        frame_ids.sort();
+
+        frame_ids.into_iter().flat_map(|frame_id| self.frame_map[frame_id].iter()).collect()
+    }
+
+    // Frames are stored under their ID3v2.4 ids
+    pub fn frames_with_id(&self, frame_id: &str) -> &[frame::Frame] {
+        self.frame_map.get(frame_id).map_or(&[], |frames| frames.as_slice())
+    }
+
+    // Every COMM frame, including ones programs use to store data
+    pub fn comments(&self) -> Vec<&frame::Comment> {
+        self.contents()
+    }
+
+    // `None` matches comments in any language
+    pub fn find_comment(&self, language: Option<&str>, description: &str) -> Option<&frame::Comment> {
+        self.comments().into_iter().find(|comment| comment_matches(comment, language, description))
+    }
+
+    // Replaces the comment with the same language and description
+    pub fn set_comment_frame(&mut self, comment: frame::Comment) {
+        let (language, description) = (comment.language.clone(), comment.description.clone());
+        self.replace_content(comment, |other| comment_matches(other, Some(&language), &description));
+    }
+
+    pub fn remove_comments(&mut self, language: Option<&str>, description: &str) {
+        self.remove_contents(|comment| comment_matches(comment, language, description));
+    }
+
+    pub fn all_lyrics(&self) -> Vec<&frame::Lyrics> {
+        self.contents()
+    }
+
+    // Prefers lyrics in the given language, falling back to the first lyrics in the tag
+    pub fn lyrics(&self, language: Option<&str>) -> Option<&frame::Lyrics> {
+        let lyrics = self.all_lyrics();
+        lyrics.iter()
+            .find(|lyrics| language.is_some_and(|language| lyrics.language == language))
+            .or_else(|| lyrics.first())
+            .cloned()
+    }
+
+    // Replaces the lyrics with the same language and description
+    pub fn set_lyrics(&mut self, lyrics: frame::Lyrics) {
+        let (language, description) = (lyrics.language.clone(), lyrics.description.clone());
+        self.replace_content(lyrics, |other| other.language == language && other.description == description);
+    }
+
+    // `None` removes the lyrics in every language
+    pub fn remove_lyrics(&mut self, language: Option<&str>) {
+        match language {
+            Some(language) => self.remove_contents(|lyrics: &frame::Lyrics| lyrics.language == language),
+            None => self.remove("USLT")
+        }
+    }
+
+    pub fn user_texts(&self) -> Vec<&frame::UserText> {
+        self.contents()
+    }
+
+    pub fn user_text(&self, description: &str) -> Option<&frame::UserText> {
+        self.user_texts().into_iter().find(|text| text.description == description)
+    }
+
+    // Replaces the TXXX frame with the same description
+    pub fn set_user_text(&mut self, description: &str, values: Vec<String>) {
+        self.replace_content(frame::UserText::new(description, values), |text| text.description == description);
+    }
+
+    pub fn remove_user_text(&mut self, description: &str) {
+        self.remove_contents(|text: &frame::UserText| text.description == description);
+    }
+
+    // Only artist pages (WOAR) can have several links, there's at most one of the others
+    pub fn links(&self, link_type: frame::LinkType) -> Vec<&str> {
+        self.frames_with_id(link_type.frame_id()).iter().filter_map(|frame| match frame.sub {
+            frame::SubClass::Link(ref url) => Some(url.as_str()),
+            _ => None
+        }).collect()
+    }
+
+    // An empty list removes the links
+    pub fn set_links(&mut self, link_type: frame::LinkType, urls: Vec<String>) {
+        self.remove(link_type.frame_id());
+        for url in urls {
+            self.push(link_type.frame_id(), frame::SubClass::Link(url));
+        }
+    }
+
+    pub fn user_links(&self) -> Vec<&frame::UserLink> {
+        self.contents()
+    }
+
+    // Replaces the WXXX frame with the same description
+    pub fn set_user_link(&mut self, link: frame::UserLink) {
+        let description = link.description.clone();
+        self.replace_content(link, |other| other.description == description);
+    }
+
+    pub fn remove_user_link(&mut self, description: &str) {
+        self.remove_contents(|link: &frame::UserLink| link.description == description);
+    }
+
+    pub fn objects(&self) -> Vec<&frame::GeneralObject> {
+        self.contents()
+    }
+
+    // Replaces the object with the same description
+    pub fn add_object(&mut self, object: frame::GeneralObject) {
+        let description = object.description.clone();
+        self.replace_content(object, |other| other.description == description);
+    }
+
+    pub fn remove_object(&mut self, description: &str) {
+        self.remove_contents(|object: &frame::GeneralObject| object.description == description);
+    }
+
+    // An owner can have several PRIV frames
+    pub fn private_frames(&self, owner: &str) -> Vec<&frame::Private> {
+        self.contents::<frame::Private>().into_iter().filter(|private| private.owner == owner).collect()
+    }
+
+    pub fn add_private_frame(&mut self, private: frame::Private) {
+        self.push("PRIV", frame::SubClass::Private(private));
+    }
+
+    pub fn remove_private_frames(&mut self, owner: &str) {
+        self.remove_contents(|private: &frame::Private| private.owner == owner);
+    }
+
+    pub fn ownership(&self) -> Option<&frame::Ownership> {
+        match self.get("OWNE") {
+            Some(frame::SubClass::Ownership(ownership)) => Some(ownership),
+            _ => None
+        }
+    }
+
+    pub fn set_ownership(&mut self, ownership: frame::Ownership) {
+        self.insert("OWNE", frame::SubClass::Ownership(ownership));
+    }
+
+    pub fn remove_ownership(&mut self) {
+        self.remove("OWNE");
+    }
+
+    pub fn is_podcast(&self) -> bool {
+        self.frame_map.contains_key("PCST")
+    }
+
+    pub fn set_podcast(&mut self, podcast: bool) {
+        if podcast {
+            self.insert("PCST", frame::SubClass::Podcast(0));
+        } else {
+            self.remove("PCST");
+        }
+    }
+
+    // RVAD frames from v2.3 tags are included as well
+    pub fn relative_volumes(&self) -> Vec<&frame::RelativeVolume> {
+        self.contents()
+    }
+
+    pub fn relative_volume(&self, identification: &str) -> Option<&frame::RelativeVolume> {
+        self.relative_volumes().into_iter().find(|volume| volume.identification == identification)
+    }
+
+    // Replaces the adjustment with the same identification
+    pub fn set_relative_volume(&mut self, volume: frame::RelativeVolume) {
+        let identification = volume.identification.clone();
+        self.replace_content(volume, |other| other.identification == identification);
+    }
+
+    pub fn remove_relative_volume(&mut self, identification: &str) {
+        self.remove_contents(|volume: &frame::RelativeVolume| volume.identification == identification);
+    }
+
+    pub fn chapters(&self) -> Vec<&frame::Chapter> {
+        self.contents()
+    }
+
+    pub fn chapter(&self, element_id: &str) -> Option<&frame::Chapter> {
+        self.chapters().into_iter().find(|chapter| chapter.element_id == element_id)
+    }
+
+    // Replaces the chapter with the same element id
+    pub fn set_chapter(&mut self, chapter: frame::Chapter) {
+        let element_id = chapter.element_id.clone();
+        self.replace_content(chapter, |other| other.element_id == element_id);
+    }
+
+    pub fn remove_chapter(&mut self, element_id: &str) {
+        self.remove_contents(|chapter: &frame::Chapter| chapter.element_id == element_id);
+    }
+
+    pub fn tables_of_contents(&self) -> Vec<&frame::TableOfContents> {
+        self.contents()
+    }
+
+    pub fn top_level_table_of_contents(&self) -> Option<&frame::TableOfContents> {
+        self.tables_of_contents().into_iter().find(|toc| toc.top_level)
+    }
+
+    // Replaces the table of contents with the same element id
+    pub fn set_table_of_contents(&mut self, toc: frame::TableOfContents) {
+        let element_id = toc.element_id.clone();
+        self.replace_content(toc, |other| other.element_id == element_id);
+    }
+
+    pub fn remove_table_of_contents(&mut self, element_id: &str) {
+        self.remove_contents(|toc: &frame::TableOfContents| toc.element_id == element_id);
+    }
+
+    pub fn unique_file_ids(&self) -> Vec<&frame::UniqueFileId> {
+        self.contents()
+    }
+
+    pub fn unique_file_id(&self, owner: &str) -> Option<&frame::UniqueFileId> {
+        self.unique_file_ids().into_iter().find(|ufid| ufid.owner == owner)
+    }
+
+    // Replaces the identifier with the same owner
+    pub fn set_unique_file_id(&mut self, ufid: frame::UniqueFileId) {
+        let owner = ufid.owner.clone();
+        self.replace_content(ufid, |other| other.owner == owner);
+    }
+
+    pub fn remove_unique_file_id(&mut self, owner: &str) {
+        self.remove_contents(|ufid: &frame::UniqueFileId| ufid.owner == owner);
+    }
+
+    // MusicBrainz stores the recording id in a UFID frame and the rest in TXXX frames
+    pub fn musicbrainz_recording_id(&self) -> Option<String> {
+        self.unique_file_id(MUSICBRAINZ_OWNER).map(|ufid| String::from_utf8_lossy(&ufid.identifier).into_owned())
+    }
+
+    pub fn set_musicbrainz_recording_id(&mut self, id: &str) {
+        self.set_unique_file_id(frame::UniqueFileId::new(MUSICBRAINZ_OWNER, id.as_bytes().to_vec()));
+    }
+
+    pub fn musicbrainz_album_id(&self) -> Option<&str> {
+        self.first_user_text("MusicBrainz Album Id")
+    }
+
+    pub fn set_musicbrainz_album_id(&mut self, id: &str) {
+        self.set_user_text("MusicBrainz Album Id", vec![id.to_string()]);
+    }
+
+    // Tracks with several artists have an id for each of them
+    pub fn musicbrainz_artist_ids(&self) -> Vec<&str> {
+        self.user_text("MusicBrainz Artist Id").map_or(Vec::new(), |text| text.values.iter().map(|id| id.as_str()).collect())
+    }
+
+    pub fn set_musicbrainz_artist_ids(&mut self, ids: Vec<String>) {
+        self.set_user_text("MusicBrainz Artist Id", ids);
+    }
+
+    pub fn musicbrainz_release_group_id(&self) -> Option<&str> {
+        self.first_user_text("MusicBrainz Release Group Id")
+    }
+
+    pub fn set_musicbrainz_release_group_id(&mut self, id: &str) {
+        self.set_user_text("MusicBrainz Release Group Id", vec![id.to_string()]);
+    }
+
+    fn first_user_text(&self, description: &str) -> Option<&str> {
+        self.user_text(description).and_then(|text| text.values.first()).map(|value| value.as_str())
+    }
+
+    pub fn popularimeters(&self) -> Vec<&frame::Popularimeter> {
+        self.contents()
+    }
+
+    // `None` takes the first popularimeter, whoever it belongs to
+    pub fn popularimeter(&self, email: Option<&str>) -> Option<&frame::Popularimeter> {
+        self.popularimeters().into_iter().find(|popm| email.is_none_or(|email| popm.email == email))
+    }
+
+    // Replaces the popularimeter with the same email
+    pub fn set_popularimeter(&mut self, popm: frame::Popularimeter) {
+        let email = popm.email.clone();
+        self.replace_content(popm, |other| other.email == email);
+    }
+
+    pub fn remove_popularimeter(&mut self, email: &str) {
+        self.remove_contents(|popm: &frame::Popularimeter| popm.email == email);
+    }
+
+    // The rating as 0 to 5 stars
+    pub fn rating(&self, email: Option<&str>) -> Option<u8> {
+        self.popularimeter(email).map(|popm| popm.stars())
+    }
+
+    // The play count for the email is kept
+    pub fn set_rating(&mut self, email: &str, stars: u8) {
+        let mut popm = self.popularimeter(Some(email)).cloned().unwrap_or_else(|| frame::Popularimeter::new(email, 0, 0));
+        popm.set_stars(stars);
+        self.set_popularimeter(popm);
+    }
+
+    pub fn play_count(&self) -> Option<u64> {
+        match self.get("PCNT") {
+            Some(frame::SubClass::PlayCount(count)) => Some(*count),
+            _ => None
+        }
+    }
+
+    pub fn set_play_count(&mut self, count: u64) {
+        self.insert("PCNT", frame::SubClass::PlayCount(count));
+    }
+
+    pub fn remove_play_count(&mut self) {
+        self.remove("PCNT");
+    }
+
+    pub fn synced_lyrics(&self) -> Vec<&frame::SyncedLyrics> {
+        self.contents()
+    }
+
+    // Replaces the synced lyrics with the same language and description
+    pub fn set_synced_lyrics(&mut self, lyrics: frame::SyncedLyrics) {
+        let (language, description) = (lyrics.language.clone(), lyrics.description.clone());
+        self.replace_content(lyrics, |other| other.language == language && other.description == description);
+    }
+
+    // `None` removes the synced lyrics in every language
+    pub fn remove_synced_lyrics(&mut self, language: Option<&str>) {
+        match language {
+            Some(language) => self.remove_contents(|lyrics: &frame::SyncedLyrics| lyrics.language == language),
+            None => self.remove("SYLT")
+        }
+    }
+
+    pub fn pictures(&self) -> Vec<&frame::Picture> {
+        self.contents()
+    }
+
+    // Replaces the picture with the same description
+    // There can only be one of each file icon type, so those are replaced as well
+    pub fn add_picture(&mut self, picture: frame::Picture) {
+        let (description, picture_type) = (picture.description.clone(), picture.picture_type);
+        let icon = picture_type == frame::PictureType::FileIcon || picture_type == frame::PictureType::OtherFileIcon;
+        self.replace_content(picture, |other| other.description == description || (icon && other.picture_type == picture_type));
+    }
+
+    // `None` removes every picture
+    pub fn remove_pictures(&mut self, picture_type: Option<frame::PictureType>) {
+        match picture_type {
+            Some(picture_type) => self.remove_contents(|picture: &frame::Picture| picture.picture_type == picture_type),
+            None => self.remove("APIC")
+        }
+    }
+
+    // The content of the first frame with the given id
+    fn get(&self, frame_id: &str) -> Option<&frame::SubClass> {
+        self.frame_map.get(frame_id).and_then(|frames| frames.first()).map(|frame| &frame.sub)
+    }
+
+    // Replaces every frame with the given id
+    fn insert(&mut self, frame_id: &str, sub: frame::SubClass) {
+        self.frame_map.insert(frame_id.to_string(), vec![frame::Frame::new(frame_id, sub)]);
+    }
+
+    fn remove(&mut self, frame_id: &str) {
+        self.frame_map.remove(frame_id);
+    }
+
+    // Adds a frame alongside any others with the same id
+    fn push(&mut self, frame_id: &str, sub: frame::SubClass) {
+        self.frame_map.entry(frame_id.to_string()).or_default().push(frame::Frame::new(frame_id, sub));
+    }
+
+    // Every frame holding a `T`
+    fn contents<T: frame::FrameContent>(&self) -> Vec<&T> {
+        self.frames_with_id(T::FRAME_ID).iter().filter_map(|frame| T::from_sub(&frame.sub)).collect()
+    }
+
+    // Replaces the first `T` that satisfies `matches` (see `replace`)
+    fn replace_content<T: frame::FrameContent, F: Fn(&T) -> bool>(&mut self, content: T, matches: F) {
+        self.replace(T::FRAME_ID, content.into_sub(), |sub| T::from_sub(sub).is_some_and(&matches));
+    }
+
+    // Removes every `T` that satisfies `matches`, frames which couldn't be parsed are kept
+    fn remove_contents<T: frame::FrameContent, F: Fn(&T) -> bool>(&mut self, matches: F) {
+        self.retain(T::FRAME_ID, |sub| !T::from_sub(sub).is_some_and(&matches));
+    }
+
+    // Replaces the first frame with the given id that satisfies `matches`, keeping its position
+    // Any other matching frames are removed, and the frame is added if nothing matched
+    fn replace<F: FnMut(&frame::SubClass) -> bool>(&mut self, frame_id: &str, sub: frame::SubClass, mut matches: F) {
+        let frames = self.frame_map.entry(frame_id.to_string()).or_default();
+        match frames.iter().position(|frame| matches(&frame.sub)) {
+            Some(index) => {
+                let mut position = 0;
+                frames.retain(|frame| {
+                    position += 1;
+                    position - 1 <= index || !matches(&frame.sub)
+                });
+                frames[index] = frame::Frame::new(frame_id, sub);
+            },
+            None => frames.push(frame::Frame::new(frame_id, sub))
+        }
+    }
+
+    // Removes the frames with the given id that don't satisfy `keep`
+    fn retain<F: FnMut(&frame::SubClass) -> bool>(&mut self, frame_id: &str, mut keep: F) {
+        if let Some(frames) = self.frame_map.get_mut(frame_id) {
+            frames.retain(|frame| keep(&frame.sub));
+            if frames.is_empty() {
+                self.frame_map.remove(frame_id);
+            }
+        }
+    }
+
+    // Serialize the tag as a 128 byte ID3v1.1 block
+    // Fields are Latin1 encoded and truncated to fit, the track number is only written if it fits in a byte
+    pub fn render_id3v1(&self) -> Vec<u8> {
+        let mut block = b"TAG".to_vec();
+
+        let mut field = |text: Option<String>, len: usize| {
+            let mut buf = frame::encode_text(&text.unwrap_or_default(), &frame::StringType::Latin1, false);
+            buf.resize(len, 0);
+            block.extend(buf);
+        };
+        field(meta::Tag::title(self), 30);
+        field(meta::Tag::artist(self), 30);
+        field(meta::Tag::album(self), 30);
+        field(meta::Tag::year(self).map(|year| year.to_string()), 4);
+
+        match meta::Tag::track(self) {
+            Some(track) if track > 0 && track < 256 => {
+                field(meta::Tag::comment(self), 28);
+                block.push(0);
+                block.push(track as u8);
+            },
+            _ => field(meta::Tag::comment(self), 30)
+        }
+
+        // Genres outside of the list are marked as unknown
+        let genre = self.genres().iter()
+            .find_map(|genre| meta::genre_index(genre))
+            .unwrap_or(255);
+        block.push(genre);
+
+        block
+    }
+
+    // The genres in TCON, with ID3v1 genre numbers resolved to their names
+    pub fn genres(&self) -> Vec<String> {
+        let mut genres: Vec<String> = Vec::new();
+        for genre in self.text_values("TCON").into_iter().flat_map(resolve_genres) {
+            if !genres.contains(&genre) {
+                genres.push(genre);
+            }
+        }
+        genres
+    }
+
+    // Every value of a text frame (eg. each artist in TPE1)
+    pub fn text_values(&self, frame_id: &str) -> Vec<&str> {
+        match self.get(frame_id) {
+            Some(frame::SubClass::Text(values, _)) => values.iter().map(|value| value.as_str()).collect(),
+            _ => Vec::new()
+        }
+    }
+
+    // Latin1 is upgraded to a unicode encoding when writing if the text needs it
+    // ID3v2.3 tags can't hold multiple values, so they're joined with '/' when writing
+    pub fn set_text_values(&mut self, frame_id: &str, values: Vec<String>) {
+        self.insert(frame_id, frame::SubClass::Text(values, frame::StringType::Latin1));
+    }
+
+    // Multiple values are joined with '/', the ID3v2.3 convention
+    fn text(&self, frame_id: &str) -> Option<String> {
+        match self.get(frame_id) {
+            Some(frame::SubClass::Text(values, _)) if !values.is_empty() => Some(values.join("/")),
+            _ => None
+        }
+    }
+
+    fn set_text(&mut self, frame_id: &str, text: String) {
+        self.set_text_values(frame_id, vec![text]);
+    }
+
+    // Compress frames whose body is at least `threshold` bytes (eg. artwork) when the tag is written
+    // Frames that were compressed when read stay compressed either way
+    pub fn set_compression_threshold(&mut self, threshold: Option<usize>) {
+        self.compression_threshold = threshold;
+    }
+
+    // Serialize the tag as an ID3v2.3 or ID3v2.4 tag, followed by `padding` empty bytes
+    pub fn render(&self, version: u8, padding: usize) -> Result<Vec<u8>, Error> {
+        if version != 3 && version != 4 {
+            return Err(Error::unsupported(&format!("Writing ID3v2.{} tags", version)));
+        }
+
+        // v2.3 only allows a single RVAD frame, preferably the one read from a v2.3 tag or the track adjustment
+        // Any other adjustments are kept in experimental frames
+        let rvad = self.frames_with_id("RVA2").iter()
+            .filter_map(|frame| match frame.sub {
+                frame::SubClass::RelativeVolume(ref volume) => Some((frame, volume)),
+                _ => None
+            })
+            .min_by_key(|(_, volume)| match volume.identification.to_lowercase().as_str() {
+                "" => 0,
+                "track" => 1,
+                _ => 2
+            })
+            .map(|(frame, _)| frame);
+
+        // Frames are sorted so that rendering the same tag always produces the same bytes
+        let mut frames = Vec::new();
+        for frame in self.frames() {
+            let compress = frame.flags.compression
+                || self.compression_threshold.is_some_and(|threshold| frame.data.len() >= threshold);
+            let extra_rvad = version == 3
+                && matches!(frame.sub, frame::SubClass::RelativeVolume(_))
+                && !rvad.is_some_and(|rvad| ptr::eq(rvad, frame));
+
+            let buf = if extra_rvad { frame.render_wrapped(version, compress) } else { frame.render(version, compress) };
+            if let Some(buf) = buf {
+                frames.extend(buf);
+            }
+        }
+
+        // The rest of the timestamp is split back out of TDRC
+        if version == 3 {
+            for frame in split_dates(self.get("TDRC")) {
+                frames.extend(frame.render(version, false).unwrap_or_default());
+            }
+        }
+
+        let mut buf = render_tag_header(version, frames.len() + padding);
+        buf.extend(frames);
+        buf.resize(buf.len() + padding, 0);
+        Ok(buf)
+    }
+}
+
+// ID3v2.4 replaced the year (TYER, already renamed to TDRC), date (TDAT, "DDMM") and time (TIME, "HHMM")
+// frames with a single "yyyy-MM-ddTHH:mm" timestamp
+// A date or time without a year (or a time without a date) can't be part of a timestamp and is dropped
+fn merge_dates(frame_map: &mut HashMap<String, Vec<frame::Frame>>) {
+    let mut take_field = |frame_id: &str| match frame_map.remove(frame_id)?.first()?.sub {
+        frame::SubClass::Text(ref values, _) => values.first()
+            .filter(|value| value.len() == 4 && value.bytes().all(|byte| byte.is_ascii_digit()))
+            .cloned(),
+        _ => None
+    };
+    let date = take_field("TDAT");
+    let time = take_field("TIME");
+
+    let year = frame_map.get_mut("TDRC")
+        .and_then(|frames| frames.first_mut())
+        .and_then(|frame| match frame.sub {
+            frame::SubClass::Text(ref mut values, _) => values.first_mut(),
+            _ => None
         })
+        .filter(|year| year.len() == 4);
+
+    if let (Some(year), Some(date)) = (year, date) {
+        year.push_str(&format!("-{}-{}", &date[2..], &date[..2]));
+        if let Some(time) = time {
+            year.push_str(&format!("T{}:{}", &time[..2], &time[2..]));
+        }
+    }
+}
+
+// The TDAT and TIME frames for a v2.3 tag, TYER gets the year
+fn split_dates(timestamp: Option<&frame::SubClass>) -> Vec<frame::Frame> {
+    let timestamp = match timestamp {
+        Some(frame::SubClass::Text(values, _)) => values.first().map_or("", |value| value.as_str()),
+        _ => ""
+    };
+    let field = |start: usize| timestamp.get(start..(start + 2)).filter(|field| field.bytes().all(|byte| byte.is_ascii_digit()));
+    let text = |frame_id: &str, value: String| frame::Frame::new(frame_id, frame::SubClass::Text(vec![value], frame::StringType::Latin1));
+
+    let mut frames = Vec::new();
+    if let (Some(month), Some(day)) = (field(5), field(8)) {
+        frames.push(text("TDAT", format!("{}{}", day, month)));
+        if let (Some(hour), Some(minute)) = (field(11), field(14)) {
+            frames.push(text("TIME", format!("{}{}", hour, minute)));
+        }
+    }
+    frames
+}
+
+
+
+// Anything else is stored in TXXX, which needs a printable Latin1 description
+fn valid_user_text_key(key: &str) -> bool {
+    !key.is_empty() && key.chars().all(|ch| ch.is_ascii_graphic() || ch == ' ')
+}
+
+fn comment_matches(comment: &frame::Comment, language: Option<&str>, description: &str) -> bool {
+    comment.description == description && language.is_none_or(|language| comment.language == language)
+}
+
+// ID3v2.3 genres reference ID3v1 genres as "(17)", optionally followed by a refinement (eg. "(4)Eurodisco")
+// ID3v2.4 genres are either the number or the name. A leading "((" escapes a literal '('
+fn resolve_genres(genre: &str) -> Vec<String> {
+    let mut genres = Vec::new();
+    let mut rest = genre;
+
+    while rest.starts_with('(') && !rest.starts_with("((") {
+        let end = match rest.find(')') {
+            Some(end) => end,
+            None => break
+        };
+
+        match genre_reference(&rest[1..end]) {
+            Some(genre) => genres.push(genre),
+            None => break
+        }
+        rest = &rest[(end + 1)..];
     }
 
-    pub fn default() -> Self {
-        Self{
-            frame_map: HashMap::new()
+    let refinement = if rest.starts_with("((") { &rest[1..] } else { rest };
+    if !refinement.is_empty() {
+        let genre = genre_reference(refinement).unwrap_or_else(|| refinement.to_string());
+        if !genres.contains(&genre) {
+            genres.push(genre);
         }
     }
+
+    genres
 }
 
+// "RX" and "CR" are the ID3v2 additions to the genre list
+fn genre_reference(reference: &str) -> Option<String> {
+    match reference {
+        "RX" => Some("Remix".to_string()),
+        "CR" => Some("Cover".to_string()),
+        _ => reference.parse().ok().and_then(meta::genre_name).map(|genre| genre.to_string())
+    }
+}
 
+// ID3v1 fields are Latin1, padded with nulls (or sometimes spaces)
+fn id3v1_text(buf: &[u8]) -> String {
+    let end = buf.iter().position(|ch| *ch == 0).unwrap_or(buf.len());
+    buf[..end].iter().map(|ch| *ch as char).collect::<String>().trim_end().to_string()
+}
 
-pub(crate) struct TagHeader {
+#[derive(Clone, Debug)]
+pub struct TagHeader {
     pub major_version: u8,
     pub rev_num: u8,
     pub size: u64,
     pub unsynch: bool,
     pub extended: bool,
     pub experimental: bool,
-    pub footer: bool
+    pub footer: bool,
+
+    // Only set if the `extended` flag was set
+    pub extended_header: Option<ExtendedHeader>
+}
+
+// The optional header between the tag header and the frames
+// v2.3 headers may carry a CRC and the padding size, v2.4 headers a CRC, an update flag and restrictions
+#[derive(Clone, Debug, Default)]
+pub struct ExtendedHeader {
+    // Size of the extended header on disk
+    pub size: u32,
+    pub padding_size: Option<u32>,
+    pub update: bool,
+    pub crc: Option<u32>,
+    pub restrictions: Option<TagRestrictions>,
+
+    // The CRC of the tag data, as calculated when reading
+    computed_crc: Option<u32>
+}
+
+impl ExtendedHeader {
+    // Check the stored CRC against the tag data, `None` if the tag doesn't have one
+    pub fn crc_matches(&self) -> Option<bool> {
+        Some(self.crc? == self.computed_crc?)
+    }
+}
+
+// The v2.4 tag restrictions, each field holds the raw value from the spec
+#[derive(Clone, Debug, Default)]
+pub struct TagRestrictions {
+    // 0: 128 frames/1MB, 1: 64 frames/128KB, 2: 32 frames/40KB, 3: 32 frames/4KB
+    pub tag_size: u8,
+
+    // Only Latin1 and UTF8 may be used
+    pub text_encoding: bool,
+
+    // 0: no restriction, 1: 1024 characters, 2: 128 characters, 3: 30 characters
+    pub text_size: u8,
+
+    // Only PNG and JPEG images may be used
+    pub image_encoding: bool,
+
+    // 0: no restriction, 1: 256x256 or smaller, 2: 64x64 or smaller, 3: exactly 64x64
+    pub image_size: u8
+}
+
+impl convert::From<u8> for TagRestrictions {
+    fn from(byte: u8) -> TagRestrictions {
+        TagRestrictions{
+            tag_size: byte >> 6,
+            text_encoding: byte & 0b100000 != 0,
+            text_size: (byte >> 3) & 0b11,
+            image_encoding: byte & 0b100 != 0,
+            image_size: byte & 0b11
+        }
+    }
+}
+
+fn parse_extended_header(buf: &[u8], version: u8) -> Result<ExtendedHeader, Error> {
+    let too_small = || Error::malformed(0, "ID3", "Extended header is larger than the tag");
+    if buf.len() < 6 {
+        return Err(too_small());
+    }
+
+    let mut extended = ExtendedHeader::default();
+
+    // v2.3 doesn't count the size bytes and doesn't use synchsafe integers
+    if version == 3 {
+        extended.size = BigEndian::read_u32(&buf[0..4]).saturating_add(4);
+        if extended.size < 10 || extended.size as usize > buf.len() {
+            return Err(too_small());
+        }
+
+        extended.padding_size = Some(BigEndian::read_u32(&buf[6..10]));
+        if buf[4] & 0b10000000 != 0 && extended.size >= 14 {
+            extended.crc = Some(BigEndian::read_u32(&buf[10..14]));
+        }
+
+        return Ok(extended);
+    }
+
+    extended.size = synch::int_from_buf(&buf[0..4]);
+    let flag_bytes = buf[4] as usize;
+    if (extended.size as usize) < 5 + flag_bytes || extended.size as usize > buf.len() {
+        return Err(too_small());
+    }
+
+    // Every set flag is followed by its data (prefixed with its length), in the order of the flags
+    let flags = buf[5];
+    let mut pos = 5 + flag_bytes;
+    let end = extended.size as usize;
+    for flag in &[0b1000000, 0b100000, 0b10000] {
+        if flags & flag == 0 {
+            continue;
+        }
+
+        if pos >= end || pos + 1 + buf[pos] as usize > end {
+            return Err(too_small());
+        }
+
+        let len = buf[pos] as usize;
+        let data = &buf[pos + 1..pos + 1 + len];
+        pos += 1 + len;
+
+        match *flag {
+            0b1000000 => extended.update = true,
+
+            // The CRC-32 is stored as a 35 bit synchsafe integer
+            0b100000 if len == 5 => {
+                extended.crc = Some(data.iter().fold(0_u64, |crc, byte| crc << 7 | (byte & 0x7f) as u64) as u32);
+            },
+            0b10000 if len == 1 => extended.restrictions = Some(TagRestrictions::from(data[0])),
+            _ => ()
+        }
+    }
+
+    Ok(extended)
 }
 
-fn parse_tag_header(buf: &Vec<u8>) -> Result<TagHeader, Error> {
+pub(crate) fn parse_tag_header(buf: &[u8]) -> Result<TagHeader, Error> {
     if buf.len() < 10 {
-        return Err(Error::new(ErrorKind::InvalidData, "Header too small"));
+        return Err(Error::malformed(0, "ID3", "Header too small"));
     }
 
     for byte in &buf[6..10] {
         if *byte >= 128 {
-            return Err(Error::new(ErrorKind::InvalidData, "Size byte greater than allowed 128"));
+            return Err(Error::malformed(6, "ID3", "Size byte greater than allowed 128"));
         }
     }
 
@@ -249,10 +1244,25 @@ fn parse_tag_header(buf: &Vec<u8>) -> Result<TagHeader, Error> {
         unsynch: buf[5] & 0b10000000 != 0,
         extended: buf[5] & 0b1000000 != 0,
         experimental: buf[5] & 0b100000 != 0,
-        footer: buf[5] & 0b00000 != 0
+        footer: buf[5] & 0b10000 != 0,
+        extended_header: None
     })
 }
 
+impl TagHeader {
+    // Size of the whole tag on disk, including the header and footer
+    pub fn total_size(&self) -> u64 {
+        let footer = if self.footer { sizeof_footer() as u64 } else { 0 };
+        10 + self.size + footer
+    }
+}
+
+fn render_tag_header(version: u8, size: usize) -> Vec<u8> {
+    let mut buf = vec![b'I', b'D', b'3', version, 0, 0];
+    buf.extend_from_slice(&synch::int_to_buf(size as u32));
+    buf
+}
+
 
 
 // TODO: Move this to a separate (utility?) file
@@ -262,19 +1272,18 @@ pub(crate) mod synch {
 
     // taglib:
     pub fn int_from_buf(buf: &[u8]) -> u32 {
-        let mut sum = 0 as u32;
+        let mut sum = 0_u32;
         let mut not_sync_safe = false;
         let len = min(buf.len() - 1, 3);
 
-        for i in 0..(len+1) {
-            let byte = buf[i];
+        for (i, byte) in buf.iter().enumerate().take(len + 1) {
             if byte & 0x80 != 0 {
                 not_sync_safe = true;
                 break;
             }
 
-            let addition = (buf[i] & 0x7f) as u32;
-            sum = sum | (addition << ((len - i) * 7));
+            let addition = (byte & 0x7f) as u32;
+            sum |= addition << ((len - i) * 7);
         }
 
         // Assume that the tag was written by software which doesn't maintain "synch" safety
@@ -282,7 +1291,7 @@ pub(crate) mod synch {
             if buf.len() >= 4 {
                 sum = BigEndian::read_u32(buf);
             } else {
-                let mut buf = buf.iter().cloned().collect::<Vec<u8>>();
+                let mut buf = buf.to_vec();
                 buf.resize(4, 0);
                 sum = BigEndian::read_u32(&buf);
             }
@@ -294,27 +1303,394 @@ pub(crate) mod synch {
     pub fn decode_slice(buf: &[u8]) -> Vec<u8>{
         let mut new = vec![0; buf.len()];
 
+        // Drop the 0x00 inserted after every 0xFF
         let mut dst = 0;
-        let mut last = [0, 0];
+        let mut last = 0;
         for byte in buf {
-            if last[0] != 0xff || last[1] != 0 {
+            if last != 0xff || *byte != 0 {
                 new[dst] = *byte;
                 dst += 1;
             }
 
-            last = [last[1], *byte];
+            last = *byte;
         }
 
         new.resize(dst, 0);
         new
     }
 
+    // The position in `buf` of the byte at `offset` once `buf` has been decoded
+    pub fn encoded_offset(buf: &[u8], offset: u64) -> u64 {
+        let mut decoded = 0;
+        let mut last = 0;
+        for (pos, byte) in buf.iter().enumerate() {
+            if last != 0xff || *byte != 0 {
+                if decoded == offset {
+                    return pos as u64;
+                }
+                decoded += 1;
+            }
+
+            last = *byte;
+        }
+
+        buf.len() as u64
+    }
+
+    // taglib: SynchData::fromUInt
+    pub fn int_to_buf(val: u32) -> [u8; 4] {
+        [
+            ((val >> 21) & 0x7f) as u8,
+            ((val >> 14) & 0x7f) as u8,
+            ((val >> 7) & 0x7f) as u8,
+            (val & 0x7f) as u8
+        ]
+    }
+
     // taglib: SynchData::decode
-    pub fn decode(buf: &Vec<u8>) -> Vec<u8> {
-        decode_slice(&buf)
+    pub fn decode(buf: &[u8]) -> Vec<u8> {
+        decode_slice(buf)
     }
 }
 
 fn sizeof_footer() -> usize {
     10
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use formats::meta::Tag as MetaTag;
+    use std::io::Cursor;
+
+    #[test]
+    fn property_keys_ignore_case() {
+        let mut tag = Tag::default();
+        assert!(tag.set_property("title", vec!["Title".to_string()]));
+        assert_eq!(tag.text_values("TIT2"), vec!["Title"]);
+        assert!(tag.user_texts().is_empty());
+    }
+
+    #[test]
+    fn unknown_properties_go_to_txxx() {
+        let mut tag = Tag::default();
+        assert!(tag.set_property("MY KEY", vec!["value".to_string()]));
+        assert_eq!(tag.user_text("MY KEY").unwrap().values, vec!["value"]);
+        assert_eq!(tag.properties()["MY KEY"], vec!["value"]);
+
+        let mut properties = meta::PropertyMap::new();
+        properties.insert("".to_string(), vec!["x".to_string()]);
+        properties.insert("BAD\u{1}KEY".to_string(), vec!["x".to_string()]);
+        properties.insert("ARTIST".to_string(), vec!["x".to_string()]);
+        let unsupported = tag.set_properties(&properties);
+        assert_eq!(unsupported.keys().collect::<Vec<_>>(), vec!["", "BAD\u{1}KEY"]);
+    }
+
+    #[test]
+    fn slash_values_survive_v23_to_v24() {
+        let mut tag = Tag::default();
+        tag.set_text("TPE1", "AC/DC".to_string());
+        tag.set_text_values("TCOM", vec!["One".to_string(), "Two".to_string()]);
+        tag.set_text_values("TIT2", vec!["First".to_string(), "Second".to_string()]);
+
+        let v23 = Tag::id3v2_from_file(&mut Cursor::new(tag.render(3, 0).unwrap()), 0).unwrap();
+        assert_eq!(v23.text_values("TPE1"), vec!["AC/DC"]);
+        assert_eq!(v23.text_values("TCOM"), vec!["One/Two"]);
+        assert_eq!(v23.text_values("TIT2"), vec!["First", "Second"]);
+
+        let buf = v23.render(4, 0).unwrap();
+        assert!(buf.windows(5).any(|text| text == b"AC/DC"));
+
+        let v24 = Tag::id3v2_from_file(&mut Cursor::new(buf), 0).unwrap();
+        assert_eq!(v24.text_values("TPE1"), vec!["AC/DC"]);
+        assert_eq!(v24.artist().unwrap(), "AC/DC");
+    }
+
+    #[test]
+    fn v23_genres_are_references() {
+        let mut tag = Tag::default();
+        tag.set_text_values("TCON", vec!["Rock".to_string(), "13".to_string(), "Remix".to_string(), "(Custom)".to_string()]);
+
+        let tag = Tag::id3v2_from_file(&mut Cursor::new(tag.render(3, 0).unwrap()), 0).unwrap();
+        assert_eq!(tag.text_values("TCON"), vec!["(17)(13)(RX)((Custom)"]);
+        assert_eq!(tag.genres(), vec!["Rock", "Pop", "Remix", "(Custom)"]);
+    }
+
+    #[test]
+    fn v23_dates_become_timestamps() {
+        let mut frames = Vec::new();
+        for (frame_id, text) in &[("TYER", "2017"), ("TDAT", "1703"), ("TIME", "1230"), ("TORY", "1999")] {
+            frames.extend_from_slice(frame_id.as_bytes());
+            frames.extend_from_slice(&(text.len() as u32 + 1).to_be_bytes());
+            frames.extend_from_slice(&[0, 0, 0]);
+            frames.extend_from_slice(text.as_bytes());
+        }
+        let mut buf = render_tag_header(3, frames.len());
+        buf.extend(frames);
+
+        let tag = Tag::id3v2_from_file(&mut Cursor::new(buf), 0).unwrap();
+        assert_eq!(tag.text_values("TDRC"), vec!["2017-03-17T12:30"]);
+        assert_eq!(tag.text_values("TDOR"), vec!["1999"]);
+        assert!(tag.frames_with_id("TDAT").is_empty() && tag.frames_with_id("TIME").is_empty());
+        assert_eq!(tag.year(), Some(2017));
+
+        let v24 = tag.render(4, 0).unwrap();
+        assert!(!v24.windows(4).any(|frame_id| frame_id == b"XXXX" || frame_id == b"TDAT"));
+
+        let v23 = tag.render(3, 0).unwrap();
+        assert!(v23.windows(8).any(|frame| frame == b"TDAT\0\0\0\x05"));
+        let tag = Tag::id3v2_from_file(&mut Cursor::new(v23), 0).unwrap();
+        assert_eq!(tag.text_values("TDRC"), vec!["2017-03-17T12:30"]);
+    }
+
+    #[test]
+    fn genre_references() {
+        assert_eq!(resolve_genres("(17)Rock"), vec!["Rock"]);
+        assert_eq!(resolve_genres("(4)Eurodisco"), vec!["Disco", "Eurodisco"]);
+        assert_eq!(resolve_genres("(RX)"), vec!["Remix"]);
+        assert_eq!(resolve_genres("(CR)(17)"), vec!["Cover", "Rock"]);
+        assert_eq!(resolve_genres("((literal"), vec!["(literal"]);
+        assert_eq!(resolve_genres("17"), vec!["Rock"]);
+        assert_eq!(resolve_genres("Rock"), vec!["Rock"]);
+        assert_eq!(resolve_genres("(999)"), vec!["(999)"]);
+    }
+
+    // A tag with an extended header holding `crc`, followed by `padding`
+    fn tag_with_crc(version: u8, crc: Option<u32>, padding: usize) -> Vec<u8> {
+        let mut tag = Tag::default();
+        tag.set_title("Title".to_string());
+        let frames = tag.render(version, 0).unwrap().split_off(10);
+
+        let mut checked = frames.clone();
+        checked.resize(frames.len() + if version == 3 { 0 } else { padding }, 0);
+        let crc = crc.unwrap_or_else(|| crc32fast::hash(&checked));
+
+        let mut body = if version == 3 {
+            let mut extended = vec![0, 0, 0, 10, 0x80, 0];
+            extended.extend_from_slice(&(padding as u32).to_be_bytes());
+            extended.extend_from_slice(&crc.to_be_bytes());
+            extended
+        } else {
+            let mut extended = synch::int_to_buf(12).to_vec();
+            extended.extend_from_slice(&[1, 0x20, 5]);
+            extended.extend((0..5).rev().map(|i| (crc as u64 >> (i * 7)) as u8 & 0x7f));
+            extended
+        };
+        body.extend(frames);
+        body.resize(body.len() + padding, 0);
+
+        let mut buf = render_tag_header(version, body.len());
+        buf[5] |= 0x40;
+        buf.extend(body);
+        buf
+    }
+
+    #[test]
+    fn extended_header_crc() {
+        for version in 3..5 {
+            let tag = Tag::id3v2_from_file(&mut Cursor::new(tag_with_crc(version, None, 16)), 0).unwrap();
+            let extended = tag.header().unwrap().extended_header.as_ref().unwrap();
+            assert_eq!(extended.crc_matches(), Some(true));
+            assert_eq!(tag.title().unwrap(), "Title");
+
+            // A bad CRC is reported, but the frames are still read
+            let tag = Tag::id3v2_from_file(&mut Cursor::new(tag_with_crc(version, Some(0x1234), 16)), 0).unwrap();
+            let extended = tag.header().unwrap().extended_header.as_ref().unwrap();
+            assert_eq!(extended.crc, Some(0x1234));
+            assert_eq!(extended.crc_matches(), Some(false));
+            assert_eq!(tag.title().unwrap(), "Title");
+        }
+    }
+
+    #[test]
+    fn single_rvad_in_v23() {
+        let volume = |identification: &str, gain: f64| frame::RelativeVolume::new(identification, vec![
+            frame::ChannelAdjustment{ channel: frame::ChannelType::Master, gain, peak: 0.0 }
+        ]);
+
+        let mut tag = Tag::default();
+        tag.set_relative_volume(volume("album", -2.0));
+        tag.set_relative_volume(volume("track", -4.0));
+
+        let buf = tag.render(3, 0).unwrap();
+        assert_eq!(buf.windows(4).filter(|id| *id == b"RVAD").count(), 1);
+
+        let tag = Tag::id3v2_from_file(&mut Cursor::new(buf), 0).unwrap();
+        let volumes = tag.relative_volumes();
+        assert_eq!(volumes.len(), 2);
+
+        // The track adjustment was written as RVAD, the album adjustment survives as it was
+        assert!(volumes.iter().any(|volume| volume.identification.is_empty()));
+        assert_eq!(tag.relative_volume("album").unwrap().channels[0].gain, -2.0);
+    }
+
+    #[test]
+    fn chapters_round_trip() {
+        let mut chapter = frame::Chapter::new("chp0", 0, 5000);
+        chapter.start_offset = Some(100);
+        chapter.set_title("Intro");
+
+        let mut toc = frame::TableOfContents::new("toc", vec!["chp0".to_string(), "chp1".to_string()]);
+        toc.top_level = true;
+        let mut unordered = frame::TableOfContents::new("extras", vec!["chp1".to_string()]);
+        unordered.ordered = false;
+
+        let mut tag = Tag::default();
+        tag.set_chapter(chapter);
+        tag.set_chapter(frame::Chapter::new("chp1", 5000, 9000));
+        tag.set_table_of_contents(toc);
+        tag.set_table_of_contents(unordered);
+
+        for version in 3..5 {
+            let buf = tag.render(version, 0).unwrap();
+
+            // Element id, then the flags byte (top level and ordered) and the entry count
+            assert!(buf.windows(6).any(|toc| toc == b"toc\0\x03\x02"));
+
+            let tag = Tag::id3v2_from_file(&mut Cursor::new(buf), 0).unwrap();
+            let chapter = tag.chapter("chp0").unwrap();
+            assert_eq!((chapter.start_time, chapter.end_time), (0, 5000));
+            assert_eq!((chapter.start_offset, chapter.end_offset), (Some(100), None));
+            assert_eq!(chapter.frames.len(), 1);
+            assert_eq!(chapter.frames[0].frame_id, "TIT2");
+            assert_eq!(chapter.title(), Some("Intro"));
+            assert!(tag.chapter("chp1").unwrap().frames.is_empty());
+
+            let toc = tag.top_level_table_of_contents().unwrap();
+            assert_eq!(toc.element_id, "toc");
+            assert!(toc.ordered);
+            assert_eq!(toc.children, vec!["chp0", "chp1"]);
+
+            let extras = tag.tables_of_contents().into_iter().find(|toc| toc.element_id == "extras").unwrap();
+            assert!(!extras.top_level && !extras.ordered);
+        }
+    }
+
+    #[test]
+    fn pictures_round_trip() {
+        let picture = |picture_type: frame::PictureType, description: &str, data: &[u8]| {
+            frame::Picture::new("image/png", picture_type, description, data.to_vec())
+        };
+
+        let mut tag = Tag::default();
+        tag.add_picture(picture(frame::PictureType::FrontCover, "Cover", b"front"));
+        tag.add_picture(picture(frame::PictureType::BackCover, "Back", b"back"));
+        tag.add_picture(picture(frame::PictureType::Artist, "", b"artist"));
+
+        // The same description replaces the picture, even with a different type
+        tag.add_picture(picture(frame::PictureType::Media, "Cover", b"media"));
+        assert_eq!(tag.pictures().len(), 3);
+
+        for version in 3..5 {
+            let tag = Tag::id3v2_from_file(&mut Cursor::new(tag.render(version, 0).unwrap()), 0).unwrap();
+            let pictures = tag.pictures();
+            assert_eq!(pictures.len(), 3);
+
+            let cover = pictures.iter().find(|picture| picture.description == "Cover").unwrap();
+            assert_eq!(cover.picture_type, frame::PictureType::Media);
+            assert_eq!(cover.mime_type, "image/png");
+            assert_eq!(cover.data, b"media");
+        }
+
+        tag.remove_pictures(Some(frame::PictureType::BackCover));
+        assert!(tag.pictures().iter().all(|picture| picture.description != "Back"));
+        assert_eq!(tag.pictures().len(), 2);
+
+        tag.remove_pictures(None);
+        assert!(tag.pictures().is_empty());
+    }
+
+    #[test]
+    fn unique_file_ids_round_trip() {
+        let mut tag = Tag::default();
+        tag.set_musicbrainz_recording_id("c0ffee");
+        tag.set_unique_file_id(frame::UniqueFileId::new("http://example.com", vec![0, 1, 2, 0xff]));
+        tag.set_musicbrainz_album_id("album");
+        tag.set_musicbrainz_artist_ids(vec!["one".to_string(), "two".to_string()]);
+        tag.set_musicbrainz_release_group_id("group");
+
+        for version in 3..5 {
+            let tag = Tag::id3v2_from_file(&mut Cursor::new(tag.render(version, 0).unwrap()), 0).unwrap();
+            assert_eq!(tag.unique_file_ids().len(), 2);
+            assert_eq!(tag.unique_file_id("http://example.com").unwrap().identifier, vec![0, 1, 2, 0xff]);
+
+            assert_eq!(tag.musicbrainz_recording_id().unwrap(), "c0ffee");
+            assert_eq!(tag.musicbrainz_album_id(), Some("album"));
+            assert_eq!(tag.musicbrainz_artist_ids(), vec!["one", "two"]);
+            assert_eq!(tag.musicbrainz_release_group_id(), Some("group"));
+            assert_eq!(tag.properties()["MUSICBRAINZ_TRACKID"], vec!["c0ffee"]);
+        }
+
+        // Setting the id again replaces the frame with the same owner
+        tag.set_musicbrainz_recording_id("decade");
+        assert_eq!(tag.unique_file_ids().len(), 2);
+        assert_eq!(tag.musicbrainz_recording_id().unwrap(), "decade");
+
+        tag.remove_unique_file_id("http://example.com");
+        assert_eq!(tag.unique_file_ids().len(), 1);
+    }
+
+    #[test]
+    fn binary_frames_round_trip() {
+        let mut tag = Tag::default();
+        tag.add_object(frame::GeneralObject::new("text/plain", "notes.txt", "Notes", b"old".to_vec()));
+        tag.add_object(frame::GeneralObject::new("text/plain", "notes.txt", "Notes", b"new".to_vec()));
+        tag.add_private_frame(frame::Private::new("WM/Provider", b"one".to_vec()));
+        tag.add_private_frame(frame::Private::new("WM/Provider", b"two".to_vec()));
+        tag.set_ownership(frame::Ownership::new("USD10.99", "20200131", "Record Store"));
+        tag.set_podcast(true);
+
+        for version in 3..5 {
+            let tag = Tag::id3v2_from_file(&mut Cursor::new(tag.render(version, 0).unwrap()), 0).unwrap();
+
+            // Objects are replaced by description, an owner can have several private frames
+            assert_eq!(tag.objects().len(), 1);
+            assert_eq!(tag.objects()[0].data, b"new");
+            assert_eq!(tag.private_frames("WM/Provider").len(), 2);
+
+            let ownership = tag.ownership().unwrap();
+            assert_eq!((ownership.price_paid.as_str(), ownership.purchase_date.as_str()), ("USD10.99", "20200131"));
+            assert_eq!(ownership.seller, "Record Store");
+            assert!(tag.is_podcast());
+        }
+
+        tag.remove_object("Notes");
+        tag.remove_private_frames("WM/Provider");
+        tag.remove_ownership();
+        tag.set_podcast(false);
+        assert!(tag.frames().is_empty());
+    }
+
+    #[test]
+    fn set_comment_keeps_other_comments() {
+        let mut tag = Tag::default();
+        tag.set_comment_frame(frame::Comment::new("eng", "", "English"));
+        tag.set_comment_frame(frame::Comment::new("deu", "", "German"));
+        tag.set_comment_frame(frame::Comment::new("eng", "iTunNORM", "data"));
+
+        assert!(tag.set_comment("Replaced".to_string()));
+        assert_eq!(tag.comment().unwrap(), "Replaced");
+        assert_eq!(tag.find_comment(Some("eng"), "").unwrap().text, "Replaced");
+        assert_eq!(tag.find_comment(Some("deu"), "").unwrap().text, "German");
+        assert_eq!(tag.find_comment(Some("eng"), "iTunNORM").unwrap().text, "data");
+        assert_eq!(tag.comments().len(), 3);
+    }
+
+    #[test]
+    fn lyrics_property_keeps_other_lyrics() {
+        let mut tag = Tag::default();
+        tag.set_lyrics(frame::Lyrics::new("eng", "Translation", "English"));
+        tag.set_lyrics(frame::Lyrics::new("deu", "", "German"));
+
+        assert!(tag.set_property("LYRICS", vec!["Replaced".to_string()]));
+        assert_eq!(tag.properties()["LYRICS"], vec!["Replaced"]);
+        assert_eq!(tag.all_lyrics().len(), 2);
+        assert_eq!(tag.all_lyrics()[0].text, "English");
+        assert_eq!(tag.all_lyrics()[1].language, "deu");
+
+        assert!(tag.set_property("LYRICS", Vec::new()));
+        assert_eq!(tag.all_lyrics().len(), 1);
+        assert_eq!(tag.all_lyrics()[0].description, "Translation");
+    }
+}
@@ -1,4 +1,6 @@
-
+use std::fs;
+use std::io;
+use std::path;
 
 pub(crate) fn from_ascii(buf: &[u8]) -> String {
     let idx =
@@ -15,3 +17,20 @@ pub(crate) fn from_ascii(buf: &[u8]) -> String {
 
     s
 }
+
+// Write the new contents of a file next to it and then move them over the original, so that
+// a failure part way through leaves the original file untouched
+pub(crate) fn replace_file<F: FnOnce(&mut fs::File) -> io::Result<()>>(path: &path::Path, write: F) -> io::Result<()> {
+    let mut temp_path = path.to_path_buf().into_os_string();
+    temp_path.push(".tmp");
+
+    let result = fs::File::create(&temp_path)
+        .and_then(|mut temp| write(&mut temp).and_then(|_| temp.sync_all()))
+        .and_then(|_| fs::set_permissions(&temp_path, fs::metadata(path)?.permissions()))
+        .and_then(|_| fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+
+    result
+}
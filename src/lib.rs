@@ -1,19 +1,28 @@
 
 extern crate byteorder;
+extern crate crc32fast;
+extern crate flate2;
 
 mod formats;
 
-use formats::*;
+pub use formats::*;
 
-use std::io;
+use std::io::{Read, Seek};
 use std::path::Path;
 
 // Function to abstract out the encoding details of the specific file
-pub fn load(path: &Path) -> Result<Box<File>, io::Error> {
-    match path.extension().and_then(|ext| ext.to_str()) {
-        Some("mp3") => Ok(Box::new(mpeg::File::open(path)?)),
-        Some("m4a") => Ok(Box::new(m4a::File::open(path)?)),
-        Some("mp4") => Ok(Box::new(m4a::File::open(path)?)),
-        _ => Err(io::Error::new(io::ErrorKind::Other, "Unimplemented"))
+pub fn load(path: &Path) -> Result<Box<dyn File>, Error> {
+    match detect(path)? {
+        Some(Format::Mpeg) => Ok(Box::new(mpeg::File::open(path)?)),
+        Some(Format::Mp4) => Ok(Box::new(m4a::File::open(path)?)),
+        None => Err(Error::UnsupportedFormat)
+    }
+}
+
+pub fn load_from_reader<R: Read + Seek>(reader: &mut R) -> Result<Box<dyn File>, Error> {
+    match detect_from_reader(reader)? {
+        Some(Format::Mpeg) => Ok(Box::new(mpeg::File::from_reader(reader)?)),
+        Some(Format::Mp4) => Ok(Box::new(m4a::File::from_reader(reader)?)),
+        None => Err(Error::UnsupportedFormat)
     }
 }